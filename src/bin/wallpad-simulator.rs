@@ -0,0 +1,75 @@
+use clap::Parser;
+
+use universal_wallpad_bridge::serial::packet::Manufacturer;
+
+/// Virtual wallpad bus on pseudo-terminals, for running the bridge without hardware
+#[derive(Parser)]
+#[command(author, version, long_about = None)]
+struct Args {
+    /// Select the emulated wall pad manufacturer
+    #[clap(short = 'm', long)]
+    manufacturer: Manufacturer,
+
+    /// Interval of the emulated wall pad polling in milliseconds (0: disabled)
+    #[clap(short = 'i', long, default_value_t = 1000)]
+    interval: u64,
+
+    /// Read back every received frame like an echoing RS-485 adapter
+    #[clap(short = 'e', long, default_value_t = false)]
+    echo: bool,
+
+    /// Print more various information
+    #[clap(short = 'v', default_value_t = false)]
+    various: bool,
+}
+
+#[cfg(unix)]
+fn main() {
+    use std::{thread, time::Duration};
+
+    use serialport::{TTYPort, SerialPort};
+
+    use universal_wallpad_bridge::{simulator::Simulator, models::{DeviceModel, hyundai}};
+    use universal_wallpad_bridge::serial::packet::hyundai::HyundaiPacket;
+
+    fn spawn(name: &'static str, devices: Vec<Box<dyn DeviceModel<HyundaiPacket> + Send>>, poll_interval: Option<Duration>, echo: bool, various: bool) -> thread::JoinHandle<()> {
+        let (mut master, slave) = TTYPort::pair().expect("pseudo-terminal pair opened succesfully");
+
+        println!("{} bus is ready on {}", name, slave.name().unwrap_or_default());
+
+        thread::spawn(move || {
+            let _slave = slave; // Keep the slave opened while the bridge reconnects
+
+            master.set_timeout(Duration::from_millis(10)).unwrap();
+
+            let mut simulator = Simulator::new(devices, poll_interval, echo, various);
+
+            if let Err(e) = simulator.run(&mut master) {
+                eprintln!("{} {:?}", name, e);
+            }
+        })
+    }
+
+    let args = Args::parse();
+    let poll_interval = if args.interval > 0 { Some(Duration::from_millis(args.interval)) } else { None };
+
+    let handles = match args.manufacturer {
+        Manufacturer::HyundaiHT => {
+            vec![
+                spawn("Primary", hyundai::primary_devices(), poll_interval, args.echo, args.various),
+                spawn("Secondary", hyundai::secondary_devices(), poll_interval, args.echo, args.various),
+            ]
+        }
+    };
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    let _ = Args::parse();
+
+    eprintln!("Pseudo-terminals are only available on unix");
+}
@@ -1,7 +1,9 @@
-use clap::Parser;
+use std::str::FromStr;
 
-use universal_wallpad_bridge::serial::packet::Manufacturer;
-use universal_wallpad_bridge::things::Feature;
+use clap::{Parser, ValueEnum};
+
+use universal_wallpad_bridge::serial::{packet::Manufacturer, port::PortSelector};
+use universal_wallpad_bridge::things::{Feature, payload::PayloadStyle};
 
 #[derive(Parser)] // requires `derive` feature
 #[command(author, version, about, long_about = None)]
@@ -15,10 +17,66 @@ pub(crate) struct Args {
     #[clap(short = 'f', long, value_delimiter = ',')]
     pub features: Vec<Feature>,
 
-    /// A serial port connected to the door lock and energy meter (ex. COM2 or /dev/ttyUSB1, etc...)
-    #[clap(short = 's', long, value_name = "PATH")]
+    /// Select the manufacturer of the devices on the secondary port, if it differs from the wall pad
+    #[clap(long, requires = "second_port")]
+    pub second_manufacturer: Option<Manufacturer>,
+
+    /// A serial port connected to the door lock and energy meter (ex. COM2, /dev/ttyUSB1 or usb:{vid}:{pid}:{serial}, etc...)
+    #[clap(short = 's', long, value_name = "PATH", value_parser = port_path)]
     pub second_port: Option<String>,
 
+    /// Another serial port for a segment of the bus, as `{name}={path}` or `{name}={path}@{manufacturer}` (ex. `metering=/dev/ttyUSB2`)
+    #[clap(long = "port", value_name = "NAME=PATH")]
+    pub ports: Vec<NamedPort>,
+
+    /// Bind a feature to the port its devices are wired to, as `{feature}={port}` (ex. `ventilator=ventilation`)
+    #[clap(long = "bind", value_name = "FEATURE=PORT")]
+    pub bindings: Vec<Binding>,
+
+    /// Connect to an external broker as `{host}` or `{host}:{port}` instead of starting the embedded one
+    #[clap(long, value_name = "HOST[:PORT]")]
+    pub broker: Option<String>,
+
+    /// Username on the external broker
+    #[clap(long, value_name = "USERNAME", requires = "broker")]
+    pub broker_username: Option<String>,
+
+    /// Password on the external broker. Prefer the environment variable or `--broker-password-file`, as the command line is visible to other users
+    #[clap(long, value_name = "PASSWORD", env = "BROKER_PASSWORD", hide_env_values = true, requires = "broker_username")]
+    pub broker_password: Option<String>,
+
+    /// File holding the password on the external broker
+    #[clap(long, value_name = "PATH", requires = "broker_username", conflicts_with = "broker_password")]
+    pub broker_password_file: Option<String>,
+
+    /// Client ID on the external broker, with `-homie` for the connection of the Homie device
+    #[clap(long, value_name = "ID", default_value_t = "universal-wallpad-bridge".to_string())]
+    pub broker_client_id: String,
+
+    /// Keep alive interval on the external broker, in seconds
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub broker_keep_alive: u16,
+
+    /// Connect to the external broker over TLS
+    #[clap(long, default_value_t = false, requires = "broker")]
+    pub broker_tls: bool,
+
+    /// Certificate authorities of the external broker in PEM (default: the system ones)
+    #[clap(long, value_name = "PATH", requires = "broker_tls")]
+    pub broker_ca: Option<String>,
+
+    /// Client certificate in PEM, for the external brokers requiring it
+    #[clap(long, value_name = "PATH", requires_all = ["broker_tls", "broker_key"])]
+    pub broker_cert: Option<String>,
+
+    /// Private key of the client certificate in PEM
+    #[clap(long, value_name = "PATH", requires = "broker_cert")]
+    pub broker_key: Option<String>,
+
+    /// Put all topics of the bridge under this prefix, for several bridges sharing a broker (ex. `wallpad/101-1203/`)
+    #[clap(long, value_name = "PREFIX")]
+    pub topic_prefix: Option<String>,
+
     /// Specified path for rumqttd configuration
     #[clap(short = 'r', long, value_name = "PATH", default_value_t = ("./rumqttd.toml".to_string()))]
     pub rumqttd: String,
@@ -27,15 +85,160 @@ pub(crate) struct Args {
     #[clap(short = 'i', long, default_value_t = 2)]
     pub interval: u64,
 
-    /// Enable log
+    /// Capture all packets to the file
     #[clap(short = 'l', long, value_name = "PATH")]
     pub log: Option<String>,
 
+    /// Rotate the capture file when it exceeds this size in KiB (0: never)
+    #[clap(long, value_name = "KiB", default_value_t = 10240)]
+    pub log_size: u64,
+
+    /// Number of rotated capture files to keep
+    #[clap(long, value_name = "COUNT", default_value_t = 5)]
+    pub log_files: usize,
+
+    /// Drop the frames read back within this time after writing them, for the adapters echoing their own transmissions, in milliseconds (0: disabled)
+    #[clap(long, value_name = "MILLIS", default_value_t = 0)]
+    pub echo_window: u64,
+
+    /// Publish the bus statistics of each port to `bridge/port/{name}/stats` in this interval, in seconds (0: disabled)
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub stats_interval: u64,
+
+    /// Publish the packets of unsupported devices to `raw/{port}/{device_id}/{sub_id}/{room_id}`
+    #[clap(long, default_value_t = false)]
+    pub discovery: bool,
+
+    /// Report a device `offline` on `bridge/availability/{feature}` when it has not answered for this time, in seconds (0: disabled)
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub availability_timeout: u64,
+
+    /// How the boolean states are published. The commands are accepted in any of the styles
+    #[clap(long, value_enum, value_name = "STYLE", default_value_t = PayloadStyle::TrueFalse)]
+    pub payload_style: PayloadStyle,
+
+    /// Publish all states again in this interval, in seconds, even if unchanged (0: only on changes)
+    #[clap(long, value_name = "SECS", default_value_t = 0)]
+    pub state_refresh: u64,
+
+    /// Publish all states of each device at once as a JSON document on `{device}/state`, besides the topic of each state
+    #[clap(long, default_value_t = false)]
+    pub json_state: bool,
+
+    /// Publish the Home Assistant discovery configs of the things to `homeassistant/{component}/uwb_{id}/config`
+    #[clap(long, default_value_t = false)]
+    pub homeassistant: bool,
+
+    /// Describe the things as a Homie device on `homie/{device}`, for openHAB and the other Homie controllers
+    #[clap(long, value_name = "DEVICE", num_args = 0..=1, default_missing_value = "uwb")]
+    pub homie: Option<String>,
+
+    /// Accept raw packets on `raw/{port}/send`, for reverse engineering only
+    #[clap(long, default_value_t = false, conflicts_with = "read_only")]
+    pub raw_send: bool,
+
+    /// Only listen to the bus, nothing is written to the serial ports
+    #[clap(long, default_value_t = false)]
+    pub read_only: bool,
+
+    /// Poll the devices as the bus master, for the homes without a working wallpad
+    #[clap(long, default_value_t = false, conflicts_with = "read_only")]
+    pub master: bool,
+
+    /// Time between the polls of the bus master, in milliseconds
+    #[clap(long, value_name = "MILLIS", default_value_t = 200, requires = "master")]
+    pub master_interval: u64,
+
+    /// Answer the polls of the wallpad as these devices, whose state lives in `virtual/{feature}/{key}` (Separated by commas)
+    #[clap(long = "virtual", value_name = "FEATURE", value_delimiter = ',', conflicts_with = "read_only")]
+    pub virtual_devices: Vec<Feature>,
+
+    /// Forward the frames between the devices on the primary port and the wallpad on the secondary port.
+    /// Set `--echo-window` as well on the adapters reading back their own transmissions, or the relayed frames are forwarded back
+    #[clap(long, default_value_t = false, requires = "second_port", conflicts_with_all = ["read_only", "master", "replay"])]
+    pub mitm: bool,
+
+    /// Drop or rewrite the forwarded frames, as `{conditions} -> {action}` (ex. `to=wallpad,device=2B,data[1]=81 -> data[1]=01`)
+    #[clap(long = "rule", value_name = "RULE", requires = "mitm")]
+    pub rules: Vec<String>,
+
+    /// Replay a capture file instead of opening the serial ports
+    #[clap(long, value_name = "PATH")]
+    pub replay: Option<String>,
+
+    /// Replay the capture as fast as possible instead of the recorded timing
+    #[clap(long, default_value_t = false, requires = "replay")]
+    pub replay_fast: bool,
+
     /// Print more various information
     #[clap(short = 'v', default_value_t = false)]
     pub various: bool,
 
-    /// A serial port connected to the entire device (ex. COM1 or /dev/ttyUSB0, etc...)
-    #[clap(last = true, value_name = "PATH")]
-    pub primary_port: String,
-}
\ No newline at end of file
+    /// A serial port connected to the entire device (ex. COM1, /dev/ttyUSB0 or usb:{vid}:{pid}:{serial}, etc...)
+    #[clap(last = true, value_name = "PATH", value_parser = port_path, required_unless_present = "replay")]
+    pub primary_port: Option<String>,
+}
+
+/// A path or a USB selector of a serial port, checked before the port is opened by its thread
+fn port_path(s: &str) -> Result<String, String> {
+    match s.parse::<PortSelector>() {
+        Ok(_) => Ok(s.to_string()),
+        Err(_) => Err(format!("invalid port `{}`, expected a path or `usb:{{vid}}:{{pid}}[:{{serial}}]`", s))
+    }
+}
+
+/// A serial port given by `--port`, besides the primary and the secondary ports
+#[derive(Clone, Debug)]
+pub(crate) struct NamedPort {
+    pub name: String,
+    pub path: String,
+    pub manufacturer: Option<Manufacturer>
+}
+
+impl FromStr for NamedPort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s.split_once('=').ok_or("expected `{name}={path}`")?;
+        let (name, path) = (name.trim(), path.trim());
+
+        if name.is_empty() || name.contains(['/', '+', '#', '{', '}']) {
+            return Err(format!("invalid port name `{}`", name));
+        }
+
+        let (path, manufacturer) = match path.rsplit_once('@') {
+            Some((path, manufacturer)) => (path, Some(<Manufacturer as ValueEnum>::from_str(manufacturer.trim(), true)?)),
+            None => (path, None)
+        };
+
+        Ok(Self { name: name.to_string(), path: port_path(path)?, manufacturer })
+    }
+}
+
+/// A feature bound to a port by `--bind`, instead of the port it is usually wired to
+#[derive(Clone, Debug)]
+pub(crate) struct Binding {
+    pub feature: Feature,
+    pub port: String
+}
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (feature, port) = s.split_once('=').ok_or("expected `{feature}={port}`")?;
+
+        Ok(Self { feature: <Feature as ValueEnum>::from_str(feature.trim(), true)?, port: port.trim().to_string() })
+    }
+}
+
+impl Args {
+    /// Port the things of a feature are bound to
+    pub fn port_of(&self, feature: &Feature) -> &str {
+        self.bindings.iter()
+            .rev()
+            .find(|b| &b.feature == feature)
+            .map(|b| b.port.as_str())
+            .unwrap_or(feature.default_port())
+    }
+}
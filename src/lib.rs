@@ -1,3 +1,7 @@
 pub mod serial;
 pub mod utils;
-pub mod things;
\ No newline at end of file
+pub mod things;
+pub mod models;
+pub mod simulator;
+pub mod master;
+pub mod mqtt;
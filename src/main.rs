@@ -1,16 +1,20 @@
-
 use clap::Parser;
 use rumqttd::local::{LinkRx, LinkTx};
-use rumqttd::{Broker, Config, Notification};
+use rumqttd::{Broker, Config, protocol::{Publish, Login, LastWill, QoS}};
 
 use universal_wallpad_bridge::serial::packet::{PacketHandler, SerialPacket};
 use universal_wallpad_bridge::serial::packet::hyundai::HyundaiPacket;
-use universal_wallpad_bridge::serial::{Serial, packet::Manufacturer, ISerial};
-use universal_wallpad_bridge::things::{Feature, Channels};
+use universal_wallpad_bridge::serial::{Serial, SerialOptions, packet::Manufacturer, ISerial, capture::{CaptureWriter, CaptureEntry}, replay::{self, ReplaySink}, raw::RawInjector, stats::SerialStats, mitm::{Relay, Rule}};
+use universal_wallpad_bridge::things::{Feature, Thing, command::CommandTracker, state::StateStore, router::{Router, Pattern, Handler, RouteError}, refresh::{self, Refresh}, virtual_device::VirtualDevice, discovery::{self, DiscoveryConfig}, homie::{self, Homie, HomieNode}, availability::{self, Availability}};
+use universal_wallpad_bridge::things::{floor_heating::FloorHeating, living_room_light::LivingRoomLight, realtime_energy_meter::RealtimeEnergyMeter, ventilator::Ventilator};
+use universal_wallpad_bridge::utils;
+use universal_wallpad_bridge::mqtt::{MqttLink, MqttReceiver, client::{Client, ClientReceiver, ClientOptions, TlsOptions}, prefix::{self, PrefixedLink, PrefixedReceiver}};
+use universal_wallpad_bridge::master::Master;
+use universal_wallpad_bridge::models::hyundai;
 
 mod cli;
 
-use std::{thread, sync::{Arc, Mutex}, time::Duration};
+use std::{fs, thread, sync::{Arc, Mutex, mpsc::{self, Sender}}, time::Duration};
 
 fn main() {
     let args = cli::Args::parse();
@@ -26,152 +30,329 @@ fn main() {
             .expect("initialized subscriber succesfully");
     }
 
-    let (mut broker, mut link_rx, mut link_tx) = create_broker(&args.rumqttd, args.various);
+    let prefix = match prefix::normalize(args.topic_prefix.as_deref().unwrap_or_default()) {
+        Ok(v) => v,
+        Err(e) => panic!("Topic prefix must be a topic without wildcards, {:?}", e)
+    };
 
-    let mut topic_handlers = Vec::new();
-    let mut tasks = Vec::new();
+    let (connected_tx, connected) = mpsc::channel();
+
+    // Kept open as long as the bridge runs, for its last will
+    let _homie_connection = match (&args.broker, &args.homie) {
+        (Some(address), Some(device_id)) => Some(connect_homie(&args, address, device_id)),
+        _ => None
+    };
+
+    let (broker, mut link_rx, mut link_tx): (Option<Broker>, Box<dyn MqttReceiver>, Box<dyn MqttLink>) = match &args.broker {
+        Some(address) => {
+            let (client, receiver) = connect_broker(&args, address, &prefix, connected_tx);
 
-    let mut pri_pkt_handlers = Vec::new();
-    let mut sec_pkt_handlers = Vec::new();
+            (None, Box::new(receiver), Box::new(client))
+        },
+        None => {
+            let (broker, link_rx, link_tx) = create_broker(&args.rumqttd, args.various);
+
+            (Some(broker), Box::new(link_rx), Box::new(link_tx))
+        }
+    };
+
+    if !prefix.is_empty() {
+        link_rx = Box::new(PrefixedReceiver::new(link_rx, &prefix));
+        link_tx = Box::new(PrefixedLink::new(link_tx, &prefix));
+    }
 
     if args.various {
-        link_tx.subscribe("#").unwrap();
+        link_tx.subscribe_absolute("#").unwrap();
     }
 
-    match args.manufacturer {
-        Manufacturer::HyundaiHT => {
-            if !args.features.is_empty() {
+    if args.homeassistant && !args.various {
+        link_tx.subscribe_absolute(discovery::STATUS_TOPIC).unwrap();
+    }
 
-                for f in &args.features {
-                    let t = f.new::<HyundaiPacket>();
+    let a_link_tx = Arc::new(Mutex::new(link_tx));
+    let commands = CommandTracker::new(a_link_tx.clone(), args.read_only);
+    let states = StateStore::new(a_link_tx.clone());
+    let availability = if args.availability_timeout > 0 {
+        Some(Availability::new(a_link_tx.clone(), Duration::from_secs(args.availability_timeout)))
+    } else {
+        None
+    };
 
-                    let h = t.handler();
-                    if h.is_primary() {
-                        pri_pkt_handlers.push(h);
-                    } else {
-                        sec_pkt_handlers.push(h);
-                    }
-                    
-                    topic_handlers.push(t.topic_handler());
+    let capture = args.log.as_ref().map(|path| {
+        match CaptureWriter::open(path, args.log_size * 1024, args.log_files) {
+            Ok(v) => Arc::new(Mutex::new(v)),
+            Err(e) => panic!("Capture file must be writable, {:?}", e)
+        }
+    });
 
-                    let tk = t.task();
-                    if tk.is_some() {
-                        tasks.push(tk.unwrap());
-                    }
+    let entries = args.replay.as_ref().map(|path| {
+        match replay::read_capture(path) {
+            Ok(v) => v,
+            Err(e) => panic!("Capture file must be readable, {:?}", e)
+        }
+    });
 
-                    if !args.various {
-                        t.subscribe(&mut link_tx)
-                    }
+    let (pri_relay, sec_relay) = if args.mitm {
+        let rules = args.rules.iter()
+            .map(|v| match v.parse::<Rule>() {
+                Ok(rule) => rule,
+                Err(e) => panic!("Rule must be `{{conditions}} -> {{action}}`, {} {:?}", v, e)
+            })
+            .collect();
 
-                }
-            } else {
-                let things = Feature::defaults::<HyundaiPacket>();
-                
-                for t in things {
-                    let h = t.handler();
-                    if h.is_primary() {
-                        pri_pkt_handlers.push(h);
-                    } else {
-                        sec_pkt_handlers.push(h);
-                    }
+        let (devices, wallpad) = Relay::pair(rules);
 
-                    topic_handlers.push(t.topic_handler());
+        (Some(devices), Some(wallpad))
+    } else {
+        (None, None)
+    };
 
-                    let tk = t.task();
-                    if tk.is_some() {
-                        tasks.push(tk.unwrap());
-                    }
+    let shared = Shared {
+        link_tx: a_link_tx.clone(),
+        commands: commands.clone(),
+        states: states.clone(),
+        availability: availability.clone(),
+        entries
+    };
 
-                    if !args.various {
-                        t.subscribe(&mut link_tx)
-                    }
-                }
-            }
+    let options = SerialOptions {
+        delay: Duration::from_millis(10),
+        print_various: args.various,
+        capture,
+        discovery: args.discovery,
+        echo_window: Duration::from_millis(args.echo_window),
+        read_only: args.read_only,
+        json_state: args.json_state,
+        style: args.payload_style
+    };
+
+    let mut ports = vec![
+        create_port(&args, PortSettings {
+            name: "primary".to_string(),
+            path: args.primary_port.clone().unwrap_or_default(),
+            manufacturer: args.manufacturer.clone(),
+            options: options.clone(),
+            relay: pri_relay
+        }, &shared)
+    ];
+
+    if args.second_port.is_some() || args.replay.is_some() {
+        ports.push(create_port(&args, PortSettings {
+            name: "secondary".to_string(),
+            path: args.second_port.clone().unwrap_or_default(),
+            manufacturer: args.second_manufacturer.clone().unwrap_or(args.manufacturer.clone()),
+            options: SerialOptions {
+                delay: Duration::from_millis(if args.mitm { 10 } else { 800 }), // Faces the wallpad in the passthrough
+                ..options.clone()
+            },
+            relay: sec_relay
+        }, &shared));
+    }
+
+    for port in &args.ports {
+        if ports.iter().any(|p| p.name == port.name) {
+            panic!("Port name must be unique, {:?}", port.name);
         }
+
+        ports.push(create_port(&args, PortSettings {
+            name: port.name.clone(),
+            path: port.path.clone(),
+            manufacturer: port.manufacturer.clone().unwrap_or(args.manufacturer.clone()),
+            options: options.clone(),
+            relay: None
+        }, &shared));
     }
 
-    thread::spawn(move || {
-        broker.start().unwrap();
-    });
+    for binding in &args.bindings {
+        if !ports.iter().any(|p| p.name == binding.port) {
+            panic!("Feature must be bound to an opened port, {:?}", binding);
+        }
+    }
 
-    let a_link_tx = Arc::new(Mutex::new(link_tx));
+    if let Some(mut broker) = broker {
+        thread::spawn(move || {
+            broker.start().unwrap();
+        });
+    }
 
-    let (pri_serial, pri_channel) = match args.manufacturer {
-        Manufacturer::HyundaiHT => create_serial::<HyundaiPacket>(
-            args.primary_port, 
-            pri_pkt_handlers, 
-            a_link_tx.clone(),
-            Duration::from_millis(10),
-            args.various
-        )
-    };
-
-    let (sec_serial, sec_channel) = if args.second_port.is_some() {
-        match args.manufacturer {
-            Manufacturer::HyundaiHT => {
-                let s = create_serial::<HyundaiPacket>(
-                    args.second_port.unwrap(), 
-                    sec_pkt_handlers,
-                    a_link_tx,
-                    Duration::from_millis(800),
-                    args.various
-                );
-
-                (Some(s.0), Some(s.1))
+    let port_stats: Vec<(String, Arc<SerialStats>)> = ports.iter()
+        .map(|p| (p.name.clone(), p.stats.clone()))
+        .collect();
+
+    let homie = args.homie.as_ref().map(|device_id| {
+        let homie = Homie::new(device_id, ports.iter().flat_map(|p| p.homie.clone()).collect());
+
+        if !args.various {
+            if let Some(mut link_tx) = utils::link_tx_lock(&a_link_tx) {
+                homie.subscribe(link_tx.as_mut());
             }
         }
+
+        homie.publish_attributes(&a_link_tx);
+        homie
+    });
+
+    let discovery: Vec<DiscoveryConfig> = if args.homeassistant {
+        ports.iter().flat_map(|p| p.discovery.clone()).collect()
     } else {
-        (None, None)
+        Vec::new()
     };
 
-    thread::spawn(move || { // Primary Serial
-        pri_serial.start();
+    DiscoveryConfig::publish_all(&discovery, &a_link_tx);
+
+    let mut tasks = Vec::new();
+    let mut refreshes = Vec::new();
+    let mut router = Router::new();
+
+    for port in ports {
+        thread::spawn(port.run); // Serial or Replay
+
+        if let Some(master) = port.master {
+            thread::spawn(master); // Master Loop
+        }
+
+        tasks.push(port.tasks);
+        refreshes.push(port.refresh);
+
+        for (pattern, handler) in port.routes {
+            let result = pattern.parse::<Pattern>().and_then(|p| router.add(&p, handler));
+
+            if let Err(e) = result {
+                panic!("Route must be a unique pattern, {} {:?}", pattern, e);
+            }
+        }
+    }
+
+    let refresh_all: Handler = Box::new(move |_, _| {
+        for refresh in &refreshes {
+            refresh();
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = router.add(&refresh::BRIDGE_TOPIC.parse::<Pattern>().unwrap(), refresh_all) {
+        panic!("Route must be a unique pattern, {} {:?}", refresh::BRIDGE_TOPIC, e);
+    }
+
+    if !args.various {
+        if let Some(mut link_tx) = utils::link_tx_lock(&a_link_tx) {
+            for filter in router.filters() {
+                link_tx.subscribe(filter).unwrap();
+            }
+        }
+    }
+
+    if !args.read_only { // The tasks poll the devices, which is left to the wallpad in the read-only mode
+        thread::spawn(move || { // Task Loop
+            loop {
+                thread::sleep(Duration::from_secs(args.interval));
+
+                for task in &tasks {
+                    task();
+                }
+            }
+        });
+    }
+
+    if args.stats_interval > 0 {
+        let link_tx_s = a_link_tx.clone();
+
+        thread::spawn(move || { // Statistics Loop
+            loop {
+                thread::sleep(Duration::from_secs(args.stats_interval));
+
+                if let Some(mut link_tx) = utils::link_tx_lock(&link_tx_s) {
+                    for (name, stats) in &port_stats {
+                        if let Err(e) = link_tx.publish(SerialStats::topic(name), stats.to_json().to_string()) {
+                            eprintln!("{:?}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let states_c = states.clone();
+
+    thread::spawn(move || { // Reconnection Loop
+        for _ in connected { // The states changed while disconnected never reached the external broker
+            states_c.republish();
+        }
     });
 
-    if sec_serial.is_some() {
-        thread::spawn(move || { // Secondary Serial
-            sec_serial.unwrap().start();
+    if args.state_refresh > 0 {
+        thread::spawn(move || { // State Refresh Loop
+            loop {
+                thread::sleep(Duration::from_secs(args.state_refresh));
+
+                states.republish();
+            }
         });
     }
-    
-    let pri_channel_t = pri_channel.clone();
-    let sec_channel_t = sec_channel.clone();
 
+    Availability::bridge_online(&a_link_tx); // Published again on every connection to an external broker
 
-    thread::spawn(move || { // Task Loop
-        loop {
-            thread::sleep(Duration::from_secs(args.interval));
+    if let Some(availability) = availability {
+        thread::spawn(move || { // Availability Loop
+            loop {
+                thread::sleep(Duration::from_secs(1));
 
-            for task in &tasks {
-                task(&pri_channel_t, &sec_channel_t.as_ref());
+                availability.expire();
             }
+        });
+    }
+
+    let commands_t = commands.clone();
+
+    thread::spawn(move || { // Command Timeout Loop
+        loop {
+            thread::sleep(Duration::from_millis(100));
+
+            commands_t.expire();
         }
     });
 
     loop { // MQTT Broker
-        let notification = match link_rx.recv().unwrap() {
+        let message = match link_rx.recv().unwrap() {
             Some(v) => v,
             None => continue,
         };
 
-        match notification {
-            Notification::Forward(forward) => {
-                for topic_handler in &topic_handlers { // Handling a topic
-                    (topic_handler)(&forward.publish, &pri_channel, &sec_channel.as_ref());
-                }
-                
-                println!(
-                    "Topic = {:?}, Retain = {:?}, Payload = {} bytes",
-                    forward.publish.topic,
-                    forward.publish.retain,
-                    forward.publish.payload.len()
-                );
-            }
-            v => {
-                println!("{v:?}");
-            }
+        if let Some(homie) = &homie {
+            homie.mirror(&message.publish, &a_link_tx);
+        }
+
+        if message.publish.topic == discovery::STATUS_TOPIC.as_bytes() && message.publish.payload == "online".as_bytes() {
+            DiscoveryConfig::publish_all(&discovery, &a_link_tx); // Home Assistant is restarted
+        }
+
+        let homie_command = homie.as_ref().and_then(|h| h.command(&message.publish));
+        let publish = homie_command.as_ref().unwrap_or(&message.publish); // Routed to the topic of the thing
+
+        let is_command = publish.topic.ends_with(b"/set");
+
+        if is_command {
+            commands.begin(publish, &message.properties);
+        }
+
+        match router.route(publish) { // Handling a topic
+            Ok(_) => (),
+            Err(RouteError::Unknown) if !is_command || args.various => (), // Not a command of the bridge
+            Err(e) => Router::report(&a_link_tx, publish, &e)
+        }
+
+        if is_command {
+            commands.end();
         }
-    }   
+
+        println!(
+            "Topic = {:?}, Retain = {:?}, Payload = {} bytes",
+            message.publish.topic,
+            message.publish.retain,
+            message.publish.payload.len()
+        );
+    }
 }
 
 fn create_broker(config_path: &str, various: bool) -> (Broker, LinkRx, LinkTx) {
@@ -192,16 +373,336 @@ fn create_broker(config_path: &str, various: bool) -> (Broker, LinkRx, LinkTx) {
     (broker, link_rx, link_tx)
 }
 
-fn create_serial<T: SerialPacket + Sized>(port_path: String, pkt_handlers: Vec<Box<dyn PacketHandler<T> + Send>>, link_tx: Arc<Mutex<LinkTx>>, delay: Duration, print_various: bool) -> (Serial<T>, Channels<T>) where Serial<T>: ISerial<T> {
-    let serial: Serial<T> = Serial::<T>::new(
-        port_path, 
+/// Connects to an external broker given as `{host}` or `{host}:{port}`, instead of the embedded one.
+/// IPv6 addresses are given in brackets with a port (ex. `[::1]:1883`).
+fn connect_broker(args: &cli::Args, address: &str, prefix: &str, connected: Sender<()>) -> (Client, ClientReceiver) {
+    let options = ClientOptions {
+        last_will: Some(LastWill {
+            topic: format!("{}{}", prefix, availability::BRIDGE_TOPIC).into(),
+            message: "offline".into(),
+            qos: QoS::AtMostOnce,
+            retain: true
+        }),
+        birth: Some(Publish::new(format!("{}{}", prefix, availability::BRIDGE_TOPIC), "online".to_string(), true)),
+        connected: Some(connected),
+        ..broker_options(args, address, args.broker_client_id.clone())
+    };
+
+    match Client::connect(options) {
+        Ok(v) => v,
+        Err(e) => panic!("Broker TLS must be configured correctly, {:?}", e)
+    }
+}
+
+/// Connects once more for the Homie device, as a connection has only one last will and the bridge one is taken by the availability.
+/// The will sets `$state` to `lost`, and the birth sets it back to `ready` on every connection.
+fn connect_homie(args: &cli::Args, address: &str, device_id: &str) -> (Client, ClientReceiver) {
+    let options = ClientOptions {
+        last_will: Some(LastWill {
+            topic: homie::state_topic(device_id).into(),
+            message: "lost".into(),
+            qos: QoS::AtMostOnce,
+            retain: true
+        }),
+        birth: Some(Publish::new(homie::state_topic(device_id), "ready".to_string(), true)),
+        ..broker_options(args, address, format!("{}-homie", args.broker_client_id))
+    };
+
+    match Client::connect(options) {
+        Ok(v) => v,
+        Err(e) => panic!("Broker TLS must be configured correctly, {:?}", e)
+    }
+}
+
+/// The options shared by the connections to the external broker, without a last will
+fn broker_options(args: &cli::Args, address: &str, client_id: String) -> ClientOptions {
+    let (host, port) = match broker_address(address) {
+        Ok((host, port)) => (host, port.unwrap_or(if args.broker_tls { 8883 } else { 1883 })),
+        Err(e) => panic!("Invalid broker address {:?}, {}", address, e)
+    };
+
+    let password = match &args.broker_password_file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(password) => Some(password.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => panic!("Failed to read the broker password from {:?}, {:?}", path, e)
+        },
+        None => args.broker_password.clone()
+    };
+
+    ClientOptions {
+        host: host.to_string(),
+        port,
+        client_id,
+        keep_alive: Duration::from_secs(args.broker_keep_alive.into()),
+        login: args.broker_username.as_ref().map(|username| Login {
+            username: username.clone(),
+            password: password.unwrap_or_default()
+        }),
+        last_will: None,
+        birth: None,
+        connected: None,
+        tls: if args.broker_tls {
+            Some(TlsOptions { ca: args.broker_ca.clone(), cert: args.broker_cert.clone(), key: args.broker_key.clone() })
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits `{host}`, `{host}:{port}`, `[{ipv6}]` or `[{ipv6}]:{port}` into the host and the port
+fn broker_address(address: &str) -> Result<(&str, Option<u16>), String> {
+    let (host, port) = match address.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err("expected `:` after the bracketed host".to_string())
+            },
+            None => return Err("missing `]`".to_string())
+        },
+        None => match address.split_once(':') {
+            Some((_, rest)) if rest.contains(':') => return Err("IPv6 addresses must be in brackets (ex. `[::1]:1883`)".to_string()),
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None)
+        }
+    };
+
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+
+    match port.map(|port| port.parse::<u16>()).transpose() {
+        Ok(port) => Ok((host, port)),
+        Err(e) => Err(format!("port must be a number, {}", e))
+    }
+}
+
+fn replay_serial<T: SerialPacket>(serial: Serial<T>, entries: &[CaptureEntry], timed: bool) where Serial<T>: ISerial<T> {
+    let sink = ReplaySink::new();
+    let mut last_pkt = None;
+
+    serial.replay(entries, timed, &sink);
+
+    loop { // Keep the channel opened for the tasks
+        thread::sleep(*serial.millis());
+        serial._drain(&sink, &mut last_pkt);
+    }
+}
+
+/// Shared by all ports
+struct Shared {
+    link_tx: Arc<Mutex<Box<dyn MqttLink>>>,
+    commands: CommandTracker,
+    states: StateStore,
+    availability: Option<Availability>,
+    /// Capture to replay instead of the serial ports
+    entries: Option<Vec<CaptureEntry>>
+}
+
+/// Settings of a port, apart from the ones shared by all ports
+struct PortSettings {
+    name: String,
+    path: String,
+    manufacturer: Manufacturer,
+    options: SerialOptions,
+    relay: Option<Relay>
+}
+
+/// A serial port with the things bound to it, hiding the packet type of its manufacturer
+struct Port {
+    name: String,
+    stats: Arc<SerialStats>,
+
+    /// Runs the serial port, or the replay of the capture
+    run: Box<dyn FnOnce() + Send>,
+    /// Polls the devices of the port as the bus master
+    master: Option<Box<dyn FnOnce() + Send>>,
+    discovery: Vec<DiscoveryConfig>,
+    homie: Vec<HomieNode>,
+    tasks: Box<dyn Fn() + Send>,
+    /// Asks all devices of the port for their states
+    refresh: Box<dyn Fn() + Send>,
+    /// Patterns of the topics handled by the things of the port
+    routes: Vec<(String, Handler)>
+}
+
+fn create_port(args: &cli::Args, settings: PortSettings, shared: &Shared) -> Port {
+    let features: Vec<Feature> = if args.mitm && settings.name == "secondary" {
+        Vec::new() // The wallpad is on the other side
+    } else if args.features.is_empty() {
+        Feature::defaults(&settings.manufacturer)
+    } else {
+        args.features.clone()
+    };
+
+    let features: Vec<Feature> = features.into_iter()
+        .filter(|f| args.port_of(f) == settings.name)
+        .collect();
+
+    match settings.manufacturer {
+        Manufacturer::HyundaiHT => {
+            let virtual_devices = if settings.name == "primary" {
+                args.virtual_devices.iter()
+                    .map(|f| {
+                        let name = f.name();
+
+                        match hyundai::virtual_device(f) {
+                            Some(device) => VirtualDevice::new(&name, device),
+                            None => panic!("{} is not available as a virtual device", name)
+                        }
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            create_things_port::<HyundaiPacket>(args, settings, &features, virtual_devices, shared)
+        }
+    }
+}
+
+fn create_things_port<T: SerialPacket + 'static>(args: &cli::Args, settings: PortSettings, features: &[Feature], virtual_devices: Vec<VirtualDevice<T>>, shared: &Shared) -> Port
+    where Serial<T>: ISerial<T>,
+          FloorHeating<T>: Thing<T>,
+          LivingRoomLight<T>: Thing<T>,
+          RealtimeEnergyMeter<T>: Thing<T>,
+          Ventilator<T>: Thing<T>,
+{
+    let things: Vec<Box<dyn Thing<T> + Send>> = features.iter().map(|f| f.new::<T>()).collect();
+
+    let mut pkt_handlers: Vec<Box<dyn PacketHandler<T> + Send>> = things.iter().zip(features)
+        .map(|(t, f)| match &shared.availability {
+            Some(availability) => availability.handler(&f.name(), t.handler()),
+            None => t.handler()
+        })
+        .collect();
+    let tasks: Vec<_> = things.iter().filter_map(|t| t.task()).collect();
+    let polls: Vec<T> = things.iter().flat_map(|t| t.polls()).collect();
+    let refreshes: Vec<Refresh<T>> = things.iter().flat_map(|t| t.refresh()).collect();
+    let discovery: Vec<DiscoveryConfig> = things.iter().zip(features)
+        .flat_map(|(t, f)| t.discovery().into_iter().map(|mut config| {
+            config.availability = shared.availability.as_ref().map(|_| Availability::topic(&f.name()));
+            config.payload_style(&args.payload_style);
+            config
+        }))
+        .collect();
+    let homie: Vec<HomieNode> = things.iter().flat_map(|t| t.homie()).collect();
+
+    let raw_injector = if args.raw_send { Some(RawInjector::<T>::new(&settings.name)) } else { None };
+
+    if let Some(injector) = &raw_injector {
+        pkt_handlers.insert(0, injector.handler());
+    }
+
+    for device in &virtual_devices {
+        pkt_handlers.insert(0, device.handler());
+    }
+
+    let mut serial: Serial<T> = Serial::<T>::new(
+        &settings.name,
+        settings.path,
         pkt_handlers,
-        link_tx,
-        delay,
-        print_various
+        shared.link_tx.clone(),
+        shared.commands.clone(),
+        shared.states.clone(),
+        settings.options
     );
+    serial.relay = settings.relay;
+
+    let channels = serial.channels.clone();
+    let stats = serial.stats().clone();
+
+    let run: Box<dyn FnOnce() + Send> = match shared.entries.clone() {
+        Some(entries) => {
+            let timed = !args.replay_fast;
+
+            Box::new(move || replay_serial(serial, &entries, timed))
+        },
+        None => Box::new(move || serial.start())
+    };
+
+    let master: Option<Box<dyn FnOnce() + Send>> = if args.master && !polls.is_empty() {
+        let master = Master::<T>::new(polls, Duration::from_millis(args.master_interval));
+        let channels = channels.clone();
+
+        Some(Box::new(move || master.run(&channels)))
+    } else {
+        None
+    };
+
+    let mut routes: Vec<(String, Handler)> = things.iter()
+        .flat_map(|t| t.routes())
+        .map(|r| (r.pattern.clone(), r.handler(&channels)))
+        .collect();
+
+    for refresh in &refreshes {
+        let (refresh, channels) = (refresh.clone(), channels.clone());
+
+        routes.push((refresh.topic(), Box::new(move |_, _| {
+            refresh.send(&channels);
+            Ok(())
+        })));
+    }
+
+    for device in virtual_devices {
+        routes.push((device.pattern(), Box::new(move |params, publish| {
+            device.update(&params.get::<String>("key")?, &publish.payload)
+        })));
+    }
+
+    if let Some(injector) = raw_injector {
+        let channels = channels.clone();
 
-    let c = serial.channels.clone();
+        routes.push((injector.send_topic(), Box::new(move |_, publish| {
+            injector.send(&publish.payload, &channels);
+            Ok(())
+        })));
+    }
+
+    Port {
+        name: settings.name,
+        stats,
+        run,
+        master,
+        discovery,
+        homie,
+        tasks: Box::new({
+            let channels = channels.clone();
+
+            move || {
+                for task in &tasks {
+                    task(&channels);
+                }
+            }
+        }),
+        refresh: Box::new(move || {
+            for refresh in &refreshes {
+                refresh.send(&channels);
+            }
+        }),
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broker_address_splits_hosts() {
+        assert_eq!(broker_address("localhost"), Ok(("localhost", None)));
+        assert_eq!(broker_address("10.0.0.2:1884"), Ok(("10.0.0.2", Some(1884))));
+        assert_eq!(broker_address("[::1]"), Ok(("::1", None)));
+        assert_eq!(broker_address("[fe80::1]:8883"), Ok(("fe80::1", Some(8883))));
+    }
 
-    (serial, c)
-}
\ No newline at end of file
+    #[test]
+    fn broker_address_rejects_invalid() {
+        assert!(broker_address("::1").is_err());
+        assert!(broker_address("[::1").is_err());
+        assert!(broker_address("[::1]1883").is_err());
+        assert!(broker_address(":1883").is_err());
+        assert!(broker_address("localhost:port").is_err());
+        assert!(broker_address("localhost:65536").is_err());
+    }
+}
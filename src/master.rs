@@ -0,0 +1,28 @@
+use std::{thread, time::Duration};
+
+use crate::{serial::packet::SerialPacket, things::Channels};
+
+/// Takes the place of a removed wallpad, polling the devices of a port in turn so they keep reporting their states.
+pub struct Master<T: SerialPacket> {
+    pub polls: Vec<T>,
+    /// Time between two requests, as the wallpad does not poll the next device right away
+    pub interval: Duration
+}
+
+impl<T: SerialPacket> Master<T> {
+    pub fn new(polls: Vec<T>, interval: Duration) -> Self {
+        Self { polls, interval }
+    }
+
+    pub fn run(&self, channels: &Channels<T>) {
+        if self.polls.is_empty() {
+            return;
+        }
+
+        for packet in self.polls.iter().cycle() {
+            thread::sleep(self.interval);
+
+            channels.send(packet.clone());
+        }
+    }
+}
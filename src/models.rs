@@ -0,0 +1,23 @@
+use crate::serial::packet::SerialPacket;
+
+pub mod hyundai;
+
+/// A device on the bus which answers the packets addressed to it, run by the simulator or standing in for a missing device as a virtual one.
+pub trait DeviceModel<T: SerialPacket> {
+    fn device_id(&self) -> u8;
+
+    /// Requests the wallpad sends to this device on every polling cycle.
+    fn polls(&self) -> Vec<T>;
+
+    fn respond(&mut self, packet: &T) -> Option<T>;
+
+    /// State as `(key, value)`, shared over MQTT when the device is virtual
+    fn state(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Applies a state given over MQTT, `Err` tells why it is malformed
+    fn update(&mut self, key: &str, _value: &str) -> Result<(), String> {
+        Err(format!("no state `{}`", key))
+    }
+}
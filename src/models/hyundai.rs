@@ -0,0 +1,298 @@
+use std::time::{Duration, Instant};
+
+use crate::{serial::{packet::{hyundai::HyundaiPacket, Command}, data::{Data, ventilator_packet::{VentilatorDataPacket, VentilatorMode, VentilatorFanSpeed}}}, things::{Feature, payload}, utils::DurationUtils};
+
+use super::DeviceModel;
+
+fn response(request: &HyundaiPacket, data: Vec<u8>) -> HyundaiPacket {
+    HyundaiPacket::new(request.device_id, Command::Response, request.device_sub_id, request.room_id, data)
+}
+
+fn to_bcd(value: u32) -> [u8; 2] {
+    [
+        (((value / 1000 % 10) << 4) | (value / 100 % 10)) as u8,
+        (((value / 10 % 10) << 4) | (value % 10)) as u8,
+    ]
+}
+
+#[derive(Clone, Copy)]
+struct HeatingRoom {
+    power: bool,
+    current_temp: u8,
+    target_temp: u8
+}
+
+impl HeatingRoom {
+    fn to_vec(self) -> Vec<u8> {
+        vec![if self.power {0x01} else {0x04}, self.current_temp, self.target_temp]
+    }
+
+    fn drift(&mut self) {
+        let goal = if self.power { self.target_temp } else { 18 };
+
+        if self.current_temp < goal {
+            self.current_temp += 1;
+        } else if self.current_temp > goal {
+            self.current_temp -= 1;
+        }
+    }
+}
+
+/// Heating controller `0x18` with 4 rooms (`0x11`..`0x14`, `0x10` for all rooms)
+pub struct FloorHeatingController {
+    rooms: [HeatingRoom; 4]
+}
+
+impl FloorHeatingController {
+    pub fn boxed() -> Box<dyn DeviceModel<HyundaiPacket> + Send> {
+        Box::new(Self {
+            rooms: [HeatingRoom { power: false, current_temp: 20, target_temp: 23 }; 4]
+        })
+    }
+}
+
+impl DeviceModel<HyundaiPacket> for FloorHeatingController {
+    fn device_id(&self) -> u8 { 0x18 }
+
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![HyundaiPacket::new(0x18, Command::Request, 0x45, 0x10, vec![0; 2])]
+    }
+
+    fn respond(&mut self, packet: &HyundaiPacket) -> Option<HyundaiPacket> {
+        if packet.room_id == 0x10 {
+            if packet.command != Command::Request {
+                return None;
+            }
+
+            let mut data = vec![0x00];
+
+            for room in self.rooms.iter_mut() {
+                room.drift();
+                data.extend(room.to_vec());
+            }
+
+            return Some(response(packet, data));
+        }
+
+        let room = self.rooms.get_mut(packet.room_id.checked_sub(0x11)? as usize)?;
+
+        if packet.command == Command::Modify {
+            match packet.device_sub_id {
+                0x45 => room.target_temp = *packet.data.first()?,
+                0x46 => room.power = *packet.data.first()? == 0x01,
+                _ => return None
+            }
+        }
+
+        let mut data = vec![0x00];
+        data.extend(room.to_vec());
+
+        Some(response(packet, data))
+    }
+}
+
+/// Living room lights `0x19` (`0x11`, `0x12`, `0x10` for all lights)
+pub struct LivingRoomLights {
+    status: [bool; 2]
+}
+
+impl LivingRoomLights {
+    pub fn boxed() -> Box<dyn DeviceModel<HyundaiPacket> + Send> {
+        Box::new(Self { status: [false; 2] })
+    }
+}
+
+impl DeviceModel<HyundaiPacket> for LivingRoomLights {
+    fn device_id(&self) -> u8 { 0x19 }
+
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![HyundaiPacket::new(0x19, Command::Request, 0x40, 0x10, vec![0; 2])]
+    }
+
+    fn respond(&mut self, packet: &HyundaiPacket) -> Option<HyundaiPacket> {
+        if packet.device_sub_id != 0x40 {
+            return None;
+        }
+
+        let status = |v: bool| if v {0x01} else {0x02};
+
+        if packet.command == Command::Modify {
+            let value = *packet.data.first()? == 0x01;
+
+            match packet.room_id {
+                0x10 => self.status = [value; 2],
+                0x11 | 0x12 => self.status[(packet.room_id - 0x11) as usize] = value,
+                _ => return None
+            }
+        }
+
+        match packet.room_id {
+            0x10 => Some(response(packet, vec![0x00, status(self.status[0]), status(self.status[1])])),
+            0x11 | 0x12 => Some(response(packet, vec![status(self.status[(packet.room_id - 0x11) as usize]), 0x00])),
+            _ => None
+        }
+    }
+
+    fn state(&self) -> Vec<(String, String)> {
+        self.status.iter().enumerate().map(|(i, v)| (format!("{:0>2}", i + 1), v.to_string())).collect()
+    }
+
+    fn update(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let status = key.parse::<usize>().ok()
+            .and_then(|id| self.status.get_mut(id.wrapping_sub(1)))
+            .ok_or(format!("no light `{}`", key))?;
+
+        *status = payload::parse_bool(value.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Central ventilator `0x2B` (`0x11`)
+pub struct CentralVentilator {
+    mode: VentilatorMode,
+    fan_speed: u64,
+    timer: Option<(Duration, Instant)>
+}
+
+impl CentralVentilator {
+    pub fn boxed() -> Box<dyn DeviceModel<HyundaiPacket> + Send> {
+        Box::new(Self { mode: VentilatorMode::Off, fan_speed: 1, timer: None })
+    }
+
+    fn data(&mut self) -> Vec<u8> {
+        let remaining = self.timer.map(|(setting, started)| setting.saturating_sub(started.elapsed()));
+
+        if remaining == Some(Duration::ZERO) {
+            self.mode = VentilatorMode::Off;
+            self.timer = None;
+        }
+
+        VentilatorDataPacket {
+            power: Some(self.mode != VentilatorMode::Off),
+            fan_speed: Some(VentilatorFanSpeed::from(self.fan_speed)),
+            mode: Some(self.mode.clone()),
+            setting_time: self.timer.map(|(setting, _)| setting),
+            remaining_time: self.timer.and(remaining)
+        }.to_vec::<HyundaiPacket>().unwrap_or_default()
+    }
+}
+
+impl DeviceModel<HyundaiPacket> for CentralVentilator {
+    fn device_id(&self) -> u8 { 0x2B }
+
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![HyundaiPacket::new(0x2B, Command::Request, 0x40, 0x11, vec![0; 2])]
+    }
+
+    fn respond(&mut self, packet: &HyundaiPacket) -> Option<HyundaiPacket> {
+        if packet.room_id != 0x11 {
+            return None;
+        }
+
+        if packet.command == Command::Modify {
+            match (packet.device_sub_id, packet.data.as_slice()) {
+                (0x40, [0x01, ..]) => self.mode = VentilatorMode::Normal,
+                (0x40, [0x81, ..]) => self.mode = VentilatorMode::Passthrough,
+                (0x40, [0x02, ..]) => {
+                    self.mode = VentilatorMode::Off;
+                    self.timer = None;
+                },
+                (0x40, [0x05, minutes, ..]) => self.timer = Some((Duration::from_minutes(*minutes as u64), Instant::now())),
+                (0x40, [0x15, hours, minutes, ..]) => self.timer = Some((Duration::from_minutes(*hours as u64 * 60 + *minutes as u64), Instant::now())),
+                (0x42, [speed, ..]) => self.fan_speed = VentilatorFanSpeed::from_pkt::<HyundaiPacket>(*speed as u16).to_level() as u64,
+                _ => return None
+            }
+        }
+
+        let data = self.data();
+
+        Some(response(packet, data))
+    }
+
+    fn state(&self) -> Vec<(String, String)> {
+        vec![
+            ("power".to_string(), (self.mode != VentilatorMode::Off).to_string()),
+            ("mode".to_string(), self.mode.to_string()),
+            ("fan_speed".to_string(), self.fan_speed.to_string()),
+        ]
+    }
+
+    fn update(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "power" => match payload::parse_bool(value.as_bytes())? {
+                false => self.mode = VentilatorMode::Off,
+                true if self.mode == VentilatorMode::Off => self.mode = VentilatorMode::Normal,
+                true => ()
+            },
+            "mode" => self.mode = payload::parse_enum::<VentilatorMode>(value.as_bytes())?,
+            "fan_speed" => self.fan_speed = payload::parse_enum::<VentilatorFanSpeed>(value.as_bytes())?.to_level() as u64,
+            _ => return Err(format!("no state `{}`", key))
+        }
+
+        Ok(())
+    }
+}
+
+/// Realtime energy meter `0x43` (`0x11`) on the secondary line
+pub struct EnergyMeter {
+    electric: u32,
+    water: u32,
+    gas: u32
+}
+
+impl EnergyMeter {
+    pub fn boxed() -> Box<dyn DeviceModel<HyundaiPacket> + Send> {
+        Box::new(Self { electric: 320, water: 0, gas: 0 })
+    }
+}
+
+impl DeviceModel<HyundaiPacket> for EnergyMeter {
+    fn device_id(&self) -> u8 { 0x43 }
+
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        Vec::new()
+    }
+
+    fn respond(&mut self, packet: &HyundaiPacket) -> Option<HyundaiPacket> {
+        if packet.command != Command::Request || packet.device_sub_id != 0x1F || packet.room_id != 0x11 {
+            return None;
+        }
+
+        self.electric = (self.electric + 7) % 10000;
+        self.water = (self.water + 1) % 10000;
+        self.gas = (self.gas + 2) % 10000;
+
+        let mut data = vec![0; 17];
+        data[3..5].copy_from_slice(&to_bcd(self.electric));
+        data[11..13].copy_from_slice(&to_bcd(self.gas));
+        data[15..17].copy_from_slice(&to_bcd(self.water));
+
+        Some(response(packet, data))
+    }
+}
+
+/// Devices which can take the place of a missing one toward the wallpad
+pub fn virtual_device(feature: &Feature) -> Option<Box<dyn DeviceModel<HyundaiPacket> + Send>> {
+    match feature {
+        Feature::Ventilator => Some(CentralVentilator::boxed()),
+        Feature::LivingRoomLights => Some(LivingRoomLights::boxed()),
+        _ => None
+    }
+}
+
+/// Devices on the primary line
+pub fn primary_devices() -> Vec<Box<dyn DeviceModel<HyundaiPacket> + Send>> {
+    vec![
+        FloorHeatingController::boxed(),
+        LivingRoomLights::boxed(),
+        CentralVentilator::boxed(),
+    ]
+}
+
+/// Devices on the secondary line
+pub fn secondary_devices() -> Vec<Box<dyn DeviceModel<HyundaiPacket> + Send>> {
+    vec![
+        EnergyMeter::boxed(),
+    ]
+}
@@ -0,0 +1,116 @@
+use std::{io, pin::pin, future::Future, task::{Context, Poll, Wake, Waker}, sync::Arc, thread::{self, Thread}};
+
+use rumqttd::{local::{LinkTx, LinkRx, LinkError}, protocol::{self, Packet, Publish, PublishProperties, ConnectReturnCode}, Notification};
+
+pub mod client;
+pub mod prefix;
+#[cfg(test)]
+pub mod recording;
+
+#[derive(Debug)]
+pub enum MqttError {
+    Link(Box<LinkError>),
+    Io(io::Error),
+    Protocol(protocol::Error),
+    Tls(String),
+    Refused(ConnectReturnCode),
+    Disconnected
+}
+
+impl From<LinkError> for MqttError {
+    fn from(value: LinkError) -> Self { Self::Link(Box::new(value)) }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(value: io::Error) -> Self { Self::Io(value) }
+}
+
+impl From<protocol::Error> for MqttError {
+    fn from(value: protocol::Error) -> Self { Self::Protocol(value) }
+}
+
+/// A publish received on the subscribed topics
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub publish: Publish,
+    /// MQTT 5 properties, only given by the embedded broker
+    pub properties: Option<PublishProperties>
+}
+
+/// The side of a broker the bridge publishes and subscribes through, either the embedded broker or an external one.
+pub trait MqttLink: Send {
+    /// Publishes with the retain flag or MQTT 5 properties
+    fn send(&mut self, publish: Publish, properties: Option<PublishProperties>) -> Result<(), MqttError>;
+    fn subscribe(&mut self, filter: &str) -> Result<(), MqttError>;
+
+    fn publish(&mut self, topic: String, payload: String) -> Result<(), MqttError> {
+        self.send(Publish::new(topic, payload, false), None)
+    }
+
+    /// Prefix of the topics of the bridge, empty if not namespaced
+    fn prefix(&self) -> &str { "" }
+
+    /// Publishes on a topic outside of the prefix, like the ones of Home Assistant, Homie or an MQTT 5 response topic
+    fn send_absolute(&mut self, publish: Publish, properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        self.send(publish, properties)
+    }
+
+    fn subscribe_absolute(&mut self, filter: &str) -> Result<(), MqttError> {
+        self.subscribe(filter)
+    }
+}
+
+/// The side of a broker the subscribed topics are received from
+pub trait MqttReceiver: Send {
+    /// Blocks until the next notification, `None` for the ones which are not a publish
+    fn recv(&mut self) -> Result<Option<Message>, MqttError>;
+}
+
+impl MqttLink for LinkTx {
+    fn send(&mut self, publish: Publish, properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        // Only the async `send` takes the retain flag and the properties
+        block_on(LinkTx::send(self, Packet::Publish(publish, properties)))?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, filter: &str) -> Result<(), MqttError> {
+        LinkTx::subscribe(self, filter)?;
+        Ok(())
+    }
+}
+
+impl MqttReceiver for LinkRx {
+    fn recv(&mut self) -> Result<Option<Message>, MqttError> {
+        match LinkRx::recv(self)? {
+            Some(Notification::Forward(forward)) => Ok(Some(Message { publish: forward.publish, properties: forward.properties })),
+            Some(v) => {
+                println!("{v:?}");
+                Ok(None)
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+/// Wakes the thread blocked on a future
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs a future on the current thread, parking it while the future is pending
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park()
+        }
+    }
+}
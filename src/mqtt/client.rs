@@ -0,0 +1,515 @@
+use std::{io::{self, Read, Write, BufReader}, net::{TcpStream, ToSocketAddrs}, sync::{Arc, mpsc::{self, Sender, Receiver, TryRecvError}}, thread, time::{Duration, Instant}, fs::File};
+
+use bytes::BytesMut;
+use rumqttd::protocol::{self, Packet, Publish, PublishProperties, Connect, ConnAck, ConnectReturnCode, Login, LastWill, Subscribe, Filter, QoS, RetainForwardRule, PingReq, Protocol, v4::V4};
+
+use super::{MqttLink, MqttReceiver, MqttError, Message};
+
+/// Largest packet accepted from the broker
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// Time to wait before connecting again after the connection is lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time to wait for the broker to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a read may block, so the packets to send are not held back
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM file of the certificate authorities, the system ones are used if not given
+    pub ca: Option<String>,
+    /// PEM files of the client certificate and its key, for the brokers requiring them
+    pub cert: Option<String>,
+    pub key: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub keep_alive: Duration,
+    pub login: Option<Login>,
+    pub last_will: Option<LastWill>,
+    /// Published on every connection, usually clearing the last will
+    pub birth: Option<Publish>,
+    /// Notified on every connection, after the birth, as the publishes made while disconnected are dropped
+    pub connected: Option<Sender<()>>,
+    pub tls: Option<TlsOptions>
+}
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+/// Publishes and subscribes through an external broker as an MQTT 3.1.1 client.
+///
+/// The connection is kept by its own thread, which connects again and restores the subscriptions when it is lost.
+pub struct Client {
+    tx: Sender<Packet>
+}
+
+pub struct ClientReceiver {
+    rx: Receiver<Message>
+}
+
+impl Client {
+    pub fn connect(options: ClientOptions) -> Result<(Client, ClientReceiver), MqttError> {
+        let tls = match &options.tls {
+            Some(tls) => Some(Arc::new(tls_config(tls)?)),
+            None => None
+        };
+
+        let (tx, packets) = mpsc::channel();
+        let (messages, rx) = mpsc::channel();
+
+        thread::spawn(move || { // MQTT Client Loop
+            let mut filters: Vec<String> = Vec::new();
+            let mut attempts: u64 = 0;
+
+            loop {
+                attempts += 1;
+
+                match open(&options, &tls) {
+                    Ok(stream) => {
+                        println!("MQTT client is connected to {}:{}, attempt: {}", options.host, options.port, attempts);
+
+                        if let Err(e) = session(stream, &options, &packets, &messages, &mut filters) {
+                            eprintln!("MQTT client is disconnected, {:?}", e);
+                        }
+                    },
+                    Err(e) => eprintln!("MQTT client failed to connect to {}:{}, {:?}", options.host, options.port, e)
+                }
+
+                if drain(&packets, &mut filters) { // The bridge is shutting down
+                    return;
+                }
+
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+
+        Ok((Client { tx }, ClientReceiver { rx }))
+    }
+}
+
+impl MqttLink for Client {
+    fn send(&mut self, publish: Publish, _properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        // MQTT 5 properties are not available in MQTT 3.1.1
+        self.tx.send(Packet::Publish(publish, None)).map_err(|_| MqttError::Disconnected)
+    }
+
+    fn subscribe(&mut self, filter: &str) -> Result<(), MqttError> {
+        self.tx.send(Packet::Subscribe(subscribe(0, filter), None)).map_err(|_| MqttError::Disconnected)
+    }
+}
+
+impl MqttReceiver for ClientReceiver {
+    fn recv(&mut self) -> Result<Option<Message>, MqttError> {
+        self.rx.recv().map(Some).map_err(|_| MqttError::Disconnected)
+    }
+}
+
+fn subscribe(pkid: u16, filter: &str) -> Subscribe {
+    Subscribe {
+        pkid,
+        filters: vec![Filter {
+            path: filter.to_string(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe
+        }]
+    }
+}
+
+/// Keeps the subscriptions and drops the publishes queued while disconnected. Returns `true` if the client is dropped.
+fn drain(packets: &Receiver<Packet>, filters: &mut Vec<String>) -> bool {
+    loop {
+        match packets.try_recv() {
+            Ok(Packet::Subscribe(subscribe, _)) => filters.extend(subscribe.filters.into_iter().map(|f| f.path)),
+            Ok(_) => (),
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => return true
+        }
+    }
+}
+
+fn write(stream: &mut dyn Stream, packet: Packet) -> Result<(), MqttError> {
+    let mut buf = BytesMut::new();
+
+    V4.write(packet, &mut buf)?;
+    stream.write_all(&buf)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Reads the next packet, `None` if nothing arrived in `POLL_INTERVAL`
+fn read(stream: &mut dyn Stream, buf: &mut BytesMut) -> Result<Option<Packet>, MqttError> {
+    loop {
+        match V4.read_mut(buf, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(protocol::Error::InsufficientBytes(_)) => (),
+            Err(e) => return Err(e.into())
+        }
+
+        let mut chunk = [0u8; 4096];
+
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(MqttError::Disconnected),
+            Ok(len) => buf.extend_from_slice(&chunk[..len]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(e) => return Err(e.into())
+        }
+    }
+}
+
+/// Connects to the first address of the host accepting in `CONNECT_TIMEOUT`, instead of the long timeout of the system
+fn connect_tcp(options: &ClientOptions) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no address for the host");
+
+    for address in (options.host.as_str(), options.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => error = e
+        }
+    }
+
+    Err(error)
+}
+
+fn open(options: &ClientOptions, tls: &Option<Arc<rustls::ClientConfig>>) -> Result<Box<dyn Stream>, MqttError> {
+    let tcp = connect_tcp(options)?;
+    tcp.set_read_timeout(Some(POLL_INTERVAL))?;
+    tcp.set_nodelay(true)?;
+
+    let mut stream: Box<dyn Stream> = match tls {
+        Some(config) => {
+            let name = rustls::ServerName::try_from(options.host.as_str()).map_err(|e| MqttError::Tls(format!("{:?}", e)))?;
+            let connection = rustls::ClientConnection::new(config.clone(), name).map_err(|e| MqttError::Tls(format!("{:?}", e)))?;
+
+            Box::new(rustls::StreamOwned::new(connection, tcp))
+        },
+        None => Box::new(tcp)
+    };
+
+    let connect = Connect {
+        keep_alive: u16::try_from(options.keep_alive.as_secs()).unwrap_or(u16::MAX),
+        client_id: options.client_id.clone(),
+        clean_session: true
+    };
+
+    write(stream.as_mut(), Packet::Connect(connect, None, options.last_will.clone(), None, options.login.clone()))?;
+
+    let started = Instant::now();
+    let mut buf = BytesMut::new();
+
+    while started.elapsed() < options.keep_alive.max(RECONNECT_DELAY) {
+        match read(stream.as_mut(), &mut buf)? {
+            Some(Packet::ConnAck(ConnAck { code: ConnectReturnCode::Success, .. }, _)) => return Ok(stream),
+            Some(Packet::ConnAck(ConnAck { code, .. }, _)) => return Err(MqttError::Refused(code)),
+            _ => ()
+        }
+    }
+
+    Err(MqttError::Io(io::ErrorKind::TimedOut.into()))
+}
+
+fn session(mut stream: Box<dyn Stream>, options: &ClientOptions, packets: &Receiver<Packet>, messages: &Sender<Message>, filters: &mut Vec<String>) -> Result<(), MqttError> {
+    let mut pkid: u16 = 0;
+    let mut next_pkid = || {
+        pkid = pkid % u16::MAX + 1; // 0 is not a valid packet id
+        pkid
+    };
+
+    for filter in filters.iter() { // Restores the subscriptions of the last connection
+        write(stream.as_mut(), Packet::Subscribe(subscribe(next_pkid(), filter), None))?;
+    }
+
+    if let Some(birth) = &options.birth {
+        write(stream.as_mut(), Packet::Publish(birth.clone(), None))?;
+    }
+
+    if let Some(connected) = &options.connected {
+        if let Err(e) = connected.send(()) {
+            eprintln!("{:?}", e);
+        }
+    }
+
+    let mut buf = BytesMut::new();
+    let mut last_write = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+
+    loop {
+        loop {
+            match packets.try_recv() {
+                Ok(Packet::Subscribe(mut subscribe, properties)) => {
+                    filters.extend(subscribe.filters.iter().map(|f| f.path.clone()));
+                    subscribe.pkid = next_pkid();
+
+                    write(stream.as_mut(), Packet::Subscribe(subscribe, properties))?;
+                },
+                Ok(packet) => write(stream.as_mut(), packet)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(())
+            }
+
+            last_write = Instant::now();
+        }
+
+        if !options.keep_alive.is_zero() {
+            match ping_sent {
+                Some(sent) if sent.elapsed() > options.keep_alive => return Err(MqttError::Io(io::ErrorKind::TimedOut.into())),
+                None if last_write.elapsed() >= options.keep_alive / 2 => {
+                    write(stream.as_mut(), Packet::PingReq(PingReq))?;
+
+                    last_write = Instant::now();
+                    ping_sent = Some(last_write);
+                },
+                _ => ()
+            }
+        }
+
+        while let Some(packet) = read(stream.as_mut(), &mut buf)? {
+            match packet {
+                Packet::Publish(publish, _) => messages.send(Message { publish, properties: None }).map_err(|_| MqttError::Disconnected)?,
+                Packet::PingResp(_) => ping_sent = None,
+                Packet::Disconnect(..) => return Err(MqttError::Disconnected),
+                _ => ()
+            }
+        }
+    }
+}
+
+fn tls_config(options: &TlsOptions) -> Result<rustls::ClientConfig, MqttError> {
+    let pem = |path: &str| -> Result<BufReader<File>, MqttError> {
+        File::open(path).map(BufReader::new).map_err(|e| MqttError::Tls(format!("{}: {:?}", path, e)))
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+
+    let (certs, source) = match &options.ca {
+        Some(ca) => (rustls_pemfile::certs(&mut pem(ca)?)?, ca.as_str()),
+        None => (rustls_native_certs::load_native_certs()?.into_iter().map(|v| v.0).collect(), "system")
+    };
+
+    let (added, _) = roots.add_parsable_certificates(&certs);
+
+    if added == 0 {
+        return Err(MqttError::Tls(format!("{}: no certificate authority", source)));
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => {
+            let certs = rustls_pemfile::certs(&mut pem(cert)?)?.into_iter().map(rustls::Certificate).collect();
+
+            let mut reader = pem(key)?;
+
+            let key = loop {
+                match rustls_pemfile::read_one(&mut reader)? {
+                    Some(rustls_pemfile::Item::PKCS8Key(v) | rustls_pemfile::Item::RSAKey(v) | rustls_pemfile::Item::ECKey(v)) => break v,
+                    Some(_) => continue,
+                    None => return Err(MqttError::Tls(format!("{}: no private key", key)))
+                }
+            };
+
+            builder.with_client_auth_cert(certs, rustls::PrivateKey(key)).map_err(|e| MqttError::Tls(format!("{:?}", e)))
+        },
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(MqttError::Tls("both of the client certificate and the key are required".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use rumqttd::protocol::PingResp;
+
+    use super::*;
+
+    /// The side of the broker of a connection, speaking MQTT 3.1.1 over the loopback
+    struct Broker {
+        stream: TcpStream,
+        buf: BytesMut
+    }
+
+    impl Broker {
+        fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            Self { stream, buf: BytesMut::new() }
+        }
+
+        fn read(&mut self) -> Packet {
+            loop {
+                match V4.read_mut(&mut self.buf, MAX_PACKET_SIZE) {
+                    Ok(packet) => return packet,
+                    Err(protocol::Error::InsufficientBytes(_)) => (),
+                    Err(e) => panic!("{:?}", e)
+                }
+
+                let mut chunk = [0u8; 4096];
+                let len = self.stream.read(&mut chunk).unwrap();
+                assert!(len > 0, "disconnected");
+
+                self.buf.extend_from_slice(&chunk[..len]);
+            }
+        }
+
+        fn write(&mut self, packet: Packet) {
+            write(&mut self.stream, packet).unwrap();
+        }
+
+        fn accept_connect(listener: &TcpListener) -> (Self, Connect, Option<LastWill>) {
+            let mut broker = Self::accept(listener);
+
+            match broker.read() {
+                Packet::Connect(connect, _, last_will, _, _) => {
+                    broker.write(Packet::ConnAck(ConnAck { session_present: false, code: ConnectReturnCode::Success }, None));
+
+                    (broker, connect, last_will)
+                },
+                packet => panic!("expected a connect, {:?}", packet)
+            }
+        }
+    }
+
+    fn options(listener: &TcpListener, keep_alive: Duration) -> ClientOptions {
+        ClientOptions {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            client_id: "test".to_string(),
+            keep_alive,
+            login: None,
+            last_will: Some(LastWill { topic: "bridge/availability".into(), message: "offline".into(), qos: QoS::AtMostOnce, retain: true }),
+            birth: Some(Publish::new("bridge/availability", "online", true)),
+            connected: None,
+            tls: None
+        }
+    }
+
+    fn filters(packet: Packet) -> Vec<String> {
+        match packet {
+            Packet::Subscribe(subscribe, _) => subscribe.filters.into_iter().map(|f| f.path).collect(),
+            packet => panic!("expected a subscribe, {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn connects_with_the_last_will_and_publishes_the_birth() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (connected, notified) = mpsc::channel();
+
+        let (_client, _receiver) = Client::connect(ClientOptions { connected: Some(connected), ..options(&listener, Duration::from_secs(60)) }).unwrap();
+        let (mut broker, connect, last_will) = Broker::accept_connect(&listener);
+
+        assert_eq!((connect.client_id.as_str(), connect.keep_alive), ("test", 60));
+        assert_eq!(last_will.map(|w| (w.topic, w.message)), Some(("bridge/availability".into(), "offline".into())));
+
+        match broker.read() {
+            Packet::Publish(publish, _) => assert_eq!((&publish.topic[..], &publish.payload[..], publish.retain), (&b"bridge/availability"[..], &b"online"[..], true)),
+            packet => panic!("expected the birth, {:?}", packet)
+        }
+
+        notified.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn refused_connection_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = options(&listener, Duration::from_secs(60));
+
+        let broker = thread::spawn(move || {
+            let mut broker = Broker::accept(&listener);
+            broker.read();
+            broker.write(Packet::ConnAck(ConnAck { session_present: false, code: ConnectReturnCode::NotAuthorized }, None));
+        });
+
+        assert!(matches!(open(&options, &None), Err(MqttError::Refused(ConnectReturnCode::NotAuthorized))));
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn subscriptions_are_restored_after_a_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = options(&listener, Duration::from_secs(60));
+
+        let (tx, packets) = mpsc::channel();
+        let (messages, _rx) = mpsc::channel();
+        let mut client = Client { tx };
+
+        let session = thread::spawn(move || {
+            let mut filters = Vec::new();
+
+            let lost = session(open(&options, &None).unwrap(), &options, &packets, &messages, &mut filters);
+            drain(&packets, &mut filters); // Subscribed while disconnected
+
+            (lost, session(open(&options, &None).unwrap(), &options, &packets, &messages, &mut filters))
+        });
+
+        let (mut broker, _, _) = Broker::accept_connect(&listener);
+        broker.read(); // Birth
+
+        client.subscribe("heating/+/set").unwrap();
+        assert_eq!(filters(broker.read()), vec!["heating/+/set"]);
+
+        drop(broker);
+        client.subscribe("light/+/set").unwrap();
+
+        let (mut broker, _, _) = Broker::accept_connect(&listener);
+
+        // The subscription made during the reconnection may come before or after the birth
+        let restored: Vec<String> = (0..3).map(|_| broker.read()).filter(|p| !matches!(p, Packet::Publish(..))).flat_map(filters).collect();
+
+        assert_eq!(restored, vec!["heating/+/set", "light/+/set"]);
+
+        drop(client);
+        let (lost, closed) = session.join().unwrap();
+
+        assert!(lost.is_err());
+        assert!(closed.is_ok());
+    }
+
+    #[test]
+    fn pings_at_half_the_keep_alive_and_drops_without_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = options(&listener, Duration::from_secs(1));
+
+        let (_tx, packets) = mpsc::channel();
+        let (messages, _rx) = mpsc::channel();
+
+        let session = thread::spawn(move || {
+            let mut filters = Vec::new();
+
+            session(open(&options, &None).unwrap(), &options, &packets, &messages, &mut filters)
+        });
+
+        let (mut broker, _, _) = Broker::accept_connect(&listener);
+        broker.read(); // Birth
+
+        let started = Instant::now();
+        assert!(matches!(broker.read(), Packet::PingReq(_)));
+        assert!((Duration::from_millis(400)..Duration::from_millis(900)).contains(&started.elapsed()));
+
+        broker.write(Packet::PingResp(PingResp));
+
+        let started = Instant::now();
+        assert!(matches!(broker.read(), Packet::PingReq(_)));
+        assert!((Duration::from_millis(400)..Duration::from_millis(900)).contains(&started.elapsed()));
+
+        // No response to the second ping, the connection is given up after the keep alive
+        let result = session.join().unwrap();
+
+        assert!(matches!(result, Err(MqttError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut));
+        assert!(started.elapsed() < Duration::from_millis(2000));
+    }
+}
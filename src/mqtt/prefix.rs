@@ -0,0 +1,147 @@
+use rumqttd::protocol::{Publish, PublishProperties};
+
+use super::{MqttLink, MqttReceiver, MqttError, Message};
+
+/// Normalizes a topic prefix to end with a single `/`, or to be empty
+pub fn normalize(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim().trim_matches('/');
+
+    if prefix.contains(['+', '#']) {
+        return Err(format!("wildcards are not allowed in the topic prefix, {}", prefix));
+    }
+
+    Ok(if prefix.is_empty() { String::new() } else { format!("{}/", prefix) })
+}
+
+/// Puts the topics of the bridge under a prefix, so several bridges can share a broker.
+/// (ex. `heating/1/power` as `wallpad/101-1203/heating/1/power`)
+pub struct PrefixedLink {
+    link: Box<dyn MqttLink>,
+    prefix: String
+}
+
+impl PrefixedLink {
+    pub fn new(link: Box<dyn MqttLink>, prefix: &str) -> Self {
+        Self { link, prefix: prefix.to_string() }
+    }
+}
+
+impl MqttLink for PrefixedLink {
+    fn send(&mut self, mut publish: Publish, properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        publish.topic = [self.prefix.as_bytes(), &publish.topic].concat().into();
+
+        self.link.send(publish, properties)
+    }
+
+    fn subscribe(&mut self, filter: &str) -> Result<(), MqttError> {
+        self.link.subscribe(&format!("{}{}", self.prefix, filter))
+    }
+
+    fn prefix(&self) -> &str { &self.prefix }
+
+    fn send_absolute(&mut self, publish: Publish, properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        self.link.send(publish, properties)
+    }
+
+    fn subscribe_absolute(&mut self, filter: &str) -> Result<(), MqttError> {
+        self.link.subscribe(filter)
+    }
+}
+
+/// Removes the prefix from the received topics, leaving the absolute ones as they are
+pub struct PrefixedReceiver {
+    receiver: Box<dyn MqttReceiver>,
+    prefix: String
+}
+
+impl PrefixedReceiver {
+    pub fn new(receiver: Box<dyn MqttReceiver>, prefix: &str) -> Self {
+        Self { receiver, prefix: prefix.to_string() }
+    }
+}
+
+impl MqttReceiver for PrefixedReceiver {
+    fn recv(&mut self) -> Result<Option<Message>, MqttError> {
+        let mut message = self.receiver.recv()?;
+
+        if let Some(message) = &mut message {
+            if message.publish.topic.starts_with(self.prefix.as_bytes()) {
+                message.publish.topic = message.publish.topic.slice(self.prefix.len()..);
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::mqtt::recording::RecordingLink;
+
+    use super::*;
+
+    /// Gives the queued messages, then fails as a closed link
+    struct QueueReceiver(VecDeque<Option<Message>>);
+
+    impl MqttReceiver for QueueReceiver {
+        fn recv(&mut self) -> Result<Option<Message>, MqttError> {
+            self.0.pop_front().ok_or(MqttError::Disconnected)
+        }
+    }
+
+    fn message(topic: &str) -> Option<Message> {
+        Some(Message { publish: Publish::new(topic.to_string(), "on".to_string(), false), properties: None })
+    }
+
+    #[test]
+    fn normalizes_prefixes() {
+        assert_eq!(normalize("wallpad/101-1203").as_deref(), Ok("wallpad/101-1203/"));
+        assert_eq!(normalize(" /wallpad/ ").as_deref(), Ok("wallpad/"));
+        assert_eq!(normalize("").as_deref(), Ok(""));
+        assert!(normalize("wallpad/+").is_err());
+        assert!(normalize("wallpad/#").is_err());
+    }
+
+    #[test]
+    fn adds_the_prefix_on_send_and_subscribe() {
+        let link = RecordingLink::default();
+        let mut prefixed = PrefixedLink::new(Box::new(link.clone()), "wallpad/");
+
+        prefixed.send(Publish::new("heating/1/power", "true", true), None).unwrap();
+        prefixed.publish("bridge/availability".to_string(), "online".to_string()).unwrap();
+        prefixed.subscribe("heating/+/power/set").unwrap();
+
+        assert_eq!(link.get("wallpad/heating/1/power").as_deref(), Some("true"));
+        assert_eq!(link.get("wallpad/bridge/availability").as_deref(), Some("online"));
+        assert_eq!(*link.subscribed.lock().unwrap(), vec!["wallpad/heating/+/power/set"]);
+        assert_eq!(prefixed.prefix(), "wallpad/");
+    }
+
+    #[test]
+    fn keeps_the_absolute_topics() {
+        let link = RecordingLink::default();
+        let mut prefixed = PrefixedLink::new(Box::new(link.clone()), "wallpad/");
+
+        prefixed.send_absolute(Publish::new("homeassistant/climate/heating_0/config", "{}", true), None).unwrap();
+        prefixed.subscribe_absolute("homie/uwb/+/+/set").unwrap();
+
+        assert_eq!(link.get("homeassistant/climate/heating_0/config").as_deref(), Some("{}"));
+        assert_eq!(*link.subscribed.lock().unwrap(), vec!["homie/uwb/+/+/set"]);
+    }
+
+    #[test]
+    fn removes_the_prefix_on_receive() {
+        let mut receiver = PrefixedReceiver::new(Box::new(QueueReceiver(VecDeque::from([
+            message("wallpad/heating/1/power/set"),
+            message("homie/uwb/heating-1/power/set"),
+            None
+        ]))), "wallpad/");
+
+        assert_eq!(&receiver.recv().unwrap().unwrap().publish.topic[..], b"heating/1/power/set");
+        assert_eq!(&receiver.recv().unwrap().unwrap().publish.topic[..], b"homie/uwb/heating-1/power/set");
+        assert!(receiver.recv().unwrap().is_none());
+        assert!(receiver.recv().is_err());
+    }
+}
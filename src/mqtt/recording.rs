@@ -0,0 +1,56 @@
+use std::sync::{Arc, Mutex};
+
+use rumqttd::protocol::{Publish, PublishProperties};
+
+use super::{MqttLink, MqttError};
+
+/// Records the publishes and the subscriptions in place of a broker, for the tests
+#[derive(Clone, Default)]
+pub struct RecordingLink {
+    /// `(topic, payload, retain)` in the order they are published
+    pub published: Arc<Mutex<Vec<(String, String, bool)>>>,
+    pub subscribed: Arc<Mutex<Vec<String>>>
+}
+
+impl RecordingLink {
+    /// Shares this link as the things and the services take it
+    pub fn link_tx(&self) -> Arc<Mutex<Box<dyn MqttLink>>> {
+        Arc::new(Mutex::new(Box::new(self.clone())))
+    }
+
+    /// The last payload published on `topic`
+    pub fn get(&self, topic: &str) -> Option<String> {
+        self.published.lock().unwrap().iter().rev().find(|(t, _, _)| t == topic).map(|(_, v, _)| v.clone())
+    }
+
+    /// Number of the publishes on `topic`
+    pub fn count(&self, topic: &str) -> usize {
+        self.published.lock().unwrap().iter().filter(|(t, _, _)| t == topic).count()
+    }
+
+    /// The retained publishes, as the broker would keep them
+    pub fn retained(&self) -> Vec<(String, String)> {
+        self.published.lock().unwrap().iter().filter(|(_, _, r)| *r).map(|(t, v, _)| (t.clone(), v.clone())).collect()
+    }
+
+    pub fn clear(&self) {
+        self.published.lock().unwrap().clear();
+    }
+}
+
+impl MqttLink for RecordingLink {
+    fn send(&mut self, publish: Publish, _properties: Option<PublishProperties>) -> Result<(), MqttError> {
+        let topic = String::from_utf8_lossy(&publish.topic).to_string();
+        let payload = String::from_utf8_lossy(&publish.payload).to_string();
+
+        self.published.lock().unwrap().push((topic, payload, publish.retain));
+
+        Ok(())
+    }
+
+    fn subscribe(&mut self, filter: &str) -> Result<(), MqttError> {
+        self.subscribed.lock().unwrap().push(filter.to_string());
+
+        Ok(())
+    }
+}
@@ -1,19 +1,37 @@
 use core::panic;
-use std::{sync::{mpsc::{Receiver, TryRecvError, self}, Arc, Mutex}, time::Duration, thread, io};
+use std::{sync::{mpsc::{Receiver, TryRecvError, self}, Arc, Mutex}, time::{Duration, Instant}, thread, io, collections::{HashMap, VecDeque}};
 
-use rumqttd::local::LinkTx;
 
-use crate::things::Channels;
+use crate::{things::{Channels, command::CommandTracker, state::StateStore, payload::PayloadStyle}, mqtt::MqttLink, utils};
 
-use self::packet::{SerialPacket, PacketHandler, hyundai::HyundaiPacket, PacketParseError};
+use self::{packet::{SerialPacket, PacketHandler, hyundai::HyundaiPacket, PacketParseError}, capture::{CaptureWriter, CaptureEntry, Direction, UNFRAMED, ECHO}, replay::ReplaySink, stats::SerialStats, port::{PortSelector, PortError}, mitm::{Relay, RELAYED}};
 
 pub mod packet;
 pub mod data;
+pub mod capture;
+pub mod replay;
+pub mod raw;
+pub mod stats;
+pub mod port;
+pub mod mitm;
 
 
+/// Unclaimed packets counted by `(device_id, sub_id, room_id)`
+pub type DiscoveryCounts = Mutex<HashMap<(u8, u8, u8), u64>>;
+
+/// Frames written to the port, kept until their echo is read back or `echo_window` passes
+pub type TransmittedFrames = Mutex<VecDeque<(Vec<u8>, Instant)>>;
+
+/// A packet is dropped when it is not answered after this number of retries
+pub const MAX_RETRIES: u32 = 5;
+
+/// Longer silences in a capture are shortened on a timed replay
+pub const REPLAY_MAX_SILENCE: Duration = Duration::from_secs(10);
+
 pub struct Serial<T> where T: SerialPacket {
     _marker: std::marker::PhantomData<T>,
     
+    pub name: String,
     pub path: String,
     pub baud_rate: u32,
 
@@ -21,35 +39,87 @@ pub struct Serial<T> where T: SerialPacket {
     pub channels: Channels<T>,
 
     pub rx: Receiver<T>,
+    /// Responses of the virtual devices, written as soon as the frame they answer is handled
+    pub replies: Receiver<T>,
 
     pub millis: Duration,
-    pub print_various: bool
+    pub print_various: bool,
+
+    pub capture: Option<Arc<Mutex<CaptureWriter>>>,
+    pub discovery: Option<DiscoveryCounts>,
+
+    pub echo_window: Duration,
+    pub transmitted: TransmittedFrames,
+
+    pub stats: Arc<SerialStats>,
+
+    pub read_only: bool,
+    /// Forwards the frames to the other port, between the wallpad and the devices
+    pub relay: Option<Relay>
+}
+
+#[derive(Clone, Default)]
+pub struct SerialOptions {
+    /// Read timeout, the queued packets are sent after the bus is silent for it
+    pub delay: Duration,
+    pub print_various: bool,
+
+    pub capture: Option<Arc<Mutex<CaptureWriter>>>,
+    /// Publish the packets no handler claims to `raw/{port}/{device_id}/{sub_id}/{room_id}`
+    pub discovery: bool,
+    /// Drop the received frames equal to a frame written within this time (zero: disabled)
+    pub echo_window: Duration,
+    /// Never write to the port, the states come from the polling of the wallpad only
+    pub read_only: bool,
+    /// Publish a JSON document with all states of each device besides the topic of each state
+    pub json_state: bool,
+    /// How the boolean states are published
+    pub style: PayloadStyle
 }
 
 pub trait ISerial<T: SerialPacket> {
-    fn new(path: String, handlers: Vec<Box<dyn PacketHandler<T> + Send>>, link_tx: Arc<Mutex<LinkTx>>, delay: Duration, print_various: bool) -> Serial::<T> {
+    fn new(name: &str, path: String, handlers: Vec<Box<dyn PacketHandler<T> + Send>>, link_tx: Arc<Mutex<Box<dyn MqttLink>>>, commands: CommandTracker, states: StateStore, options: SerialOptions) -> Serial::<T> {
         let (tx, rx) = mpsc::channel();
+        let (reply_tx, replies) = mpsc::channel();
 
         Serial::<T> {
             _marker: std::marker::PhantomData,
     
+            name: name.to_string(),
             path: path,
             baud_rate: T::baud_rate(),
     
             handlers: handlers,
-            channels: Channels { link_tx: link_tx, serial_tx: tx },
+            channels: Channels { port: name.to_string(), link_tx, serial_tx: tx, reply_tx, commands, states, read_only: options.read_only, json_state: options.json_state, style: options.style, staging: false },
             rx: rx,
-            millis: delay,
-            print_various
+            replies,
+            millis: options.delay,
+            print_various: options.print_various,
+            capture: options.capture,
+            discovery: if options.discovery { Some(Mutex::new(HashMap::new())) } else { None },
+            echo_window: options.echo_window,
+            transmitted: Mutex::new(VecDeque::new()),
+            stats: Arc::new(SerialStats::default()),
+            read_only: options.read_only,
+            relay: None
         }
     }
 
+    fn name(&self) -> &str;
     fn path(&self) -> &String;
     fn baud_rate(&self) -> u32;
     fn handlers(&self) -> &Vec<Box<dyn PacketHandler<T> + Send>>;
     fn channels(&self) -> &Channels<T>;
     fn rx(&self) -> &Receiver<T>;
+    fn replies(&self) -> &Receiver<T>;
     fn various(&self) -> bool;
+    fn capture(&self) -> &Option<Arc<Mutex<CaptureWriter>>>;
+    fn discovery(&self) -> &Option<DiscoveryCounts>;
+    fn echo_window(&self) -> &Duration;
+    fn transmitted(&self) -> &TransmittedFrames;
+    fn stats(&self) -> &Arc<SerialStats>;
+    fn read_only(&self) -> bool;
+    fn relay(&self) -> &Option<Relay>;
 
     fn prefix(&self) -> &'static [u8];
     fn suffix(&self) -> &'static [u8];
@@ -57,21 +127,45 @@ pub trait ISerial<T: SerialPacket> {
 
     fn start(&self) {
         let i: u32 = 0;
+        let mut opened = false;
 
         'open: loop {
-            let builder = serialport::new(self.path(), self.baud_rate())
+            // USB adapters may come back on another path, so the port is found again on every attempt
+            let path = match self.path().parse::<PortSelector>().and_then(|v| v.resolve()) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Serial port of {} is not found, {:?}", self.name(), e);
+
+                    if let PortError::NotFound(_) | PortError::Ambiguous(..) = e {
+                        for (name, selector) in port::usb_ports() {
+                            eprintln!("  {} {}", name, selector);
+                        }
+                    }
+
+                    thread::sleep(Duration::from_secs(5));
+                    continue 'open;
+                }
+            };
+
+            let builder = serialport::new(&path, self.baud_rate())
                 .timeout(*self.millis());
  
 
             match builder.open() {
                 Ok(mut port) => {
-                    println!("Serial is opened on {}, baud: {}, attempt: {}", i, self.path(), self.baud_rate());
-                    println!("{:?} {:?}", self.path(), port);
+                    if opened {
+                        SerialStats::increase(&self.stats().reconnects);
+                    }
+                    opened = true;
+
+                    println!("Serial is opened on {}, baud: {}, attempt: {}", i, path, self.baud_rate());
+                    println!("{:?} {:?}", path, port);
 
                     let mut buf: Vec<u8> = Vec::new();
 
                     let mut last_pkt: Option<T> = None;
                     let mut retry_delayed = false;
+                    let mut retries: u32 = 0;
 
                     'clear: loop {
                         match self.rx().try_recv() {
@@ -88,18 +182,14 @@ pub trait ISerial<T: SerialPacket> {
                             Ok(l) => {
                                 let ref_tmp = &tmp[0..l];      
                                 if self.various() {
-                                    println!("{:?} {:?}", self.path(), ref_tmp);
+                                    println!("{:?} {:?}", path, ref_tmp);
                                 }
 
                                 if !buf.is_empty() {
                                     if ref_tmp.ends_with(self.suffix()) {
                                         buf.extend_from_slice(&ref_tmp);
                                         
-                                        retry_delayed = !self._handle(&buf, &last_pkt);
-
-                                        if !retry_delayed {
-                                            last_pkt = None;
-                                        }
+                                        retry_delayed = !self._handle(&buf, &mut last_pkt);
 
                                         buf.clear();
                                         
@@ -108,16 +198,19 @@ pub trait ISerial<T: SerialPacket> {
                                     }
                                 } else if ref_tmp.starts_with(self.prefix()) {
                                     if ref_tmp.ends_with(self.suffix()) {
-                                        retry_delayed = !self._handle(&ref_tmp[0..ref_tmp.get(1).map(|v| *v as usize).unwrap_or(l).min(l)], &last_pkt);
-
-                                        if !retry_delayed {
-                                            last_pkt = None;
-                                        }
+                                        retry_delayed = !self._handle(&ref_tmp[0..ref_tmp.get(1).map(|v| *v as usize).unwrap_or(l).min(l)], &mut last_pkt);
                                     } else {
                                         buf.clear();
                                         buf.extend_from_slice(&ref_tmp);
                                     }
                                 } else {
+                                    self._capture(Direction::Rx, UNFRAMED, ref_tmp);
+                                    SerialStats::add(&self.stats().discarded_bytes, l as u64);
+
+                                    if let Some(relay) = self.relay() {
+                                        relay.forward::<T>(None, ref_tmp);
+                                    }
+
                                     if last_pkt.is_some() {
                                         eprintln!("Error detected, retry send -> {:?}", &last_pkt);
                                         retry_delayed = true;
@@ -126,39 +219,67 @@ pub trait ISerial<T: SerialPacket> {
 
                                 let t = T::length_from_buffer(&buf);
 
-                                if t.is_some() && buf.len() >= t.unwrap() {
+                                if let Some(t) = t.filter(|t| buf.len() >= *t) {
 
-                                    retry_delayed = !self._handle(&buf[0..t.unwrap().min(buf.len())], &last_pkt);
-
-                                    if !retry_delayed {
-                                        last_pkt = None;
-                                    }
+                                    retry_delayed = !self._handle(&buf[0..t], &mut last_pkt);
      
-                                    if buf[t.unwrap()..buf.len()].starts_with(self.prefix()) {
-                                        buf = buf.split_off(t.unwrap())
+                                    if buf[t..buf.len()].starts_with(self.prefix()) {
+                                        buf = buf.split_off(t)
                                     } else {
+                                        SerialStats::add(&self.stats().discarded_bytes, (buf.len() - t) as u64);
                                         buf.clear();
                                     }
                                 }
+
+                                self._reply(&mut port);
                             },
                             Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                                if retry_delayed {
-                                    match port.write(&last_pkt.as_ref().unwrap().to_vec()) {
+                                self._relay(&mut port);
+
+                                if retry_delayed && retries >= MAX_RETRIES {
+                                    eprintln!("No response after {} retries, give up -> {:?}", retries, &last_pkt);
+                                    SerialStats::increase(&self.stats().give_ups);
+
+                                    last_pkt = None;
+                                    retry_delayed = false;
+                                    retries = 0;
+                                } else if retry_delayed {
+                                    let buf = last_pkt.as_ref().unwrap().to_vec();
+
+                                    match port.write(&buf) {
                                         Ok(c) => {
+                                            self._capture(Direction::Tx, "Retry", &buf);
+                                            self._transmitted(&buf);
+                                            SerialStats::increase(&self.stats().frames_sent);
+                                            SerialStats::increase(&self.stats().retries);
+                                            retries += 1;
                                             println!("retried -> {:?}", c);
                                             retry_delayed = false;
                                         },
-                                        Err(e) => eprintln!("{:?}", e)
+                                        Err(e) => {
+                                            self._capture(Direction::Tx, &format!("{:?}", e.kind()), &buf);
+                                            eprintln!("{:?}", e)
+                                        }
                                     };
                                 } else {
 
                                 match self.rx().try_recv() {
+                                    Ok(v) if self.read_only() => eprintln!("Read-only, drop -> {:?}", v),
                                     Ok(v) => {
-                                        match port.write(&v.to_vec()) {
+                                        let buf = v.to_vec();
+
+                                        match port.write(&buf) {
                                             Ok(_) => {
+                                                self._capture(Direction::Tx, "Ok", &buf);
+                                                self._transmitted(&buf);
+                                                SerialStats::increase(&self.stats().frames_sent);
+                                                self.channels().commands.written(self.name(), &buf);
                                                 println!("-> {:?}", v)
                                             },
-                                            Err(e) => eprintln!("{:?}", e)
+                                            Err(e) => {
+                                                self._capture(Direction::Tx, &format!("{:?}", e.kind()), &buf);
+                                                eprintln!("{:?}", e)
+                                            }
                                         }
                                         
                                         /*if c > 0 {
@@ -169,6 +290,7 @@ pub trait ISerial<T: SerialPacket> {
                                         } else {
                                             */
                                             last_pkt = Some(v);
+                                            retries = 0;
                                         //}
                                         
                                     },
@@ -190,20 +312,245 @@ pub trait ISerial<T: SerialPacket> {
             thread::sleep(Duration::from_secs(5));
         }
     }
-    fn _handle(&self, buf: &[u8], last_pkt: &Option<T>) -> bool {
-        match self.handle(&buf) {
+    /// Feeds the received frames of this port from a capture into the handlers, instead of a serial port.
+    /// Packets queued on `serial_tx` are collected into `sink`.
+    fn replay(&self, entries: &[CaptureEntry], timed: bool, sink: &ReplaySink<T>) {
+        let started = Instant::now();
+        let mut previous = entries.first().map(|e| e.timestamp).unwrap_or_default();
+        let mut at = Duration::ZERO;
+
+        let mut last_pkt: Option<T> = None;
+
+        for entry in entries {
+            // Counted over the frames of all ports, so the ports replayed by their own threads stay in step
+            at += entry.timestamp.saturating_sub(previous).min(REPLAY_MAX_SILENCE); // The time starts over with each run appended to the file
+            previous = entry.timestamp;
+
+            if entry.port != self.name() || entry.direction != Direction::Rx || entry.result == UNFRAMED || entry.result == ECHO {
+                continue;
+            }
+
+            if timed {
+                let elapsed = started.elapsed();
+
+                if at > elapsed {
+                    thread::sleep(at - elapsed);
+                }
+            }
+
+            self._drain(sink, &mut last_pkt);
+
+            self._handle(&entry.raw, &mut last_pkt);
+
+            while let Ok(v) = self.replies().try_recv() {
+                self._capture(Direction::Tx, "Reply", &v.to_vec());
+                SerialStats::increase(&self.stats().frames_sent);
+                sink.push(v);
+            }
+        }
+
+        self._drain(sink, &mut last_pkt);
+
+        println!("Replay is finished on {}, {} packets written", self.name(), sink.written().len());
+    }
+
+    fn _drain(&self, sink: &ReplaySink<T>, last_pkt: &mut Option<T>) {
+        loop {
+            match self.rx().try_recv() {
+                Ok(v) => {
+                    let buf = v.to_vec();
+                    self._capture(Direction::Tx, "Ok", &buf);
+                    SerialStats::increase(&self.stats().frames_sent);
+                    self.channels().commands.written(self.name(), &buf);
+                    println!("-> {:?}", v);
+
+                    sink.push(v.clone());
+                    *last_pkt = Some(v);
+                },
+                Err(ref e) if *e == TryRecvError::Empty => break,
+                Err(e) => panic!("Serial channel(receiver) must be opened, {:?}", e)
+            }
+        }
+    }
+
+    /// Writes the frames forwarded from the other port
+    fn _relay(&self, port: &mut dyn io::Write) {
+        if let Some(relay) = self.relay() {
+            while let Ok(buf) = relay.rx.try_recv() {
+                match port.write_all(&buf) {
+                    Ok(_) => {
+                        self._capture(Direction::Tx, "Relay", &buf);
+                        self._transmitted(&buf);
+                        SerialStats::increase(&self.stats().frames_sent);
+                    },
+                    Err(e) => {
+                        self._capture(Direction::Tx, &format!("{:?}", e.kind()), &buf);
+                        eprintln!("{:?}", e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the responses of the virtual devices to the frames just handled, without waiting for the bus to be silent
+    fn _reply(&self, port: &mut dyn io::Write) {
+        while let Ok(v) = self.replies().try_recv() {
+            let buf = v.to_vec();
+
+            match port.write_all(&buf) {
+                Ok(_) => {
+                    self._capture(Direction::Tx, "Reply", &buf);
+                    self._transmitted(&buf);
+                    SerialStats::increase(&self.stats().frames_sent);
+                    println!("reply -> {:?}", v)
+                },
+                Err(e) => {
+                    self._capture(Direction::Tx, &format!("{:?}", e.kind()), &buf);
+                    eprintln!("{:?}", e)
+                }
+            }
+        }
+    }
+
+    fn _capture(&self, direction: Direction, result: &str, buf: &[u8]) {
+        if let Some(capture) = self.capture() {
+            match capture.lock() {
+                Ok(mut capture) => capture.write(self.name(), direction, result, buf),
+                Err(e) => eprintln!("{:?}", e)
+            }
+        }
+    }
+
+    fn _transmitted(&self, buf: &[u8]) {
+        if self.echo_window().is_zero() {
+            return;
+        }
+
+        match self.transmitted().lock() {
+            Ok(mut transmitted) => transmitted.push_back((buf.to_vec(), Instant::now())),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    /// Whether the frame is our own transmission read back by the adapter
+    fn _is_echo(&self, buf: &[u8]) -> bool {
+        if self.echo_window().is_zero() {
+            return false;
+        }
+
+        match self.transmitted().lock() {
+            Ok(mut transmitted) => {
+                while transmitted.front().is_some_and(|(_, sent)| sent.elapsed() > *self.echo_window()) {
+                    transmitted.pop_front();
+                }
+
+                match transmitted.iter().position(|(frame, _)| frame == buf) {
+                    Some(i) => {
+                        transmitted.remove(i);
+                        true
+                    },
+                    None => false
+                }
+            },
+            Err(e) => {
+                eprintln!("{:?}", e);
+                false
+            }
+        }
+    }
+
+    fn _discover(&self, packet: &T, buf: &[u8]) {
+        let count = match self.discovery() {
+            Some(discovery) => match discovery.lock() {
+                Ok(mut discovery) => {
+                    let count = discovery.entry((packet.device_id(), packet.device_sub_id(), packet.room_id())).or_insert(0);
+                    *count += 1;
+                    *count
+                },
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    return;
+                }
+            },
+            None => return
+        };
+
+        let payload = serde_json::json!({
+            "command": format!("{:?}", packet.command()),
+            "data": utils::to_hex(packet.data()),
+            "raw": utils::to_hex(buf),
+            "count": count
+        });
+
+        if let Some(mut link_tx) = utils::link_tx_lock(&self.channels().link_tx) {
+            let result = link_tx.publish(
+                format!("raw/{}/{:02X}/{:02X}/{:02X}", self.name(), packet.device_id(), packet.device_sub_id(), packet.room_id()),
+                payload.to_string()
+            );
+
+            if let Err(e) = result {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+
+    /// Returns `false` when `last_pkt` has to be sent again.
+    fn _handle(&self, buf: &[u8], last_pkt: &mut Option<T>) -> bool {
+        SerialStats::increase(&self.stats().frames_received);
+
+        if self._is_echo(buf) {
+            self._capture(Direction::Rx, ECHO, buf);
+            SerialStats::increase(&self.stats().echo_drops);
+
+            return true;
+        }
+
+        let result = self.handle(buf);
+
+        let forwarded = self.relay().as_ref().map(|relay| relay.forward(result.as_ref().ok(), buf));
+
+        if let Some(forwarded) = forwarded.filter(|v| *v != RELAYED) {
+            println!("{} by rule -> {:?}", forwarded, result.as_ref().ok());
+        }
+
+        self._capture(Direction::Rx, &match (&result, forwarded) {
+            (Ok(_), Some(forwarded)) if forwarded != RELAYED => forwarded.to_string(), // The action of the rule
+            (Ok(_), _) => "Ok".to_string(),
+            (Err(e), _) => format!("{:?}", e)
+        }, buf);
+
+        match &result {
+            Ok(_) => self.stats().valid_frame(),
+            Err(e) => self.stats().parse_error(e)
+        }
+
+        match result {
             Ok(packet) => {
+                let mut claimed = false;
+
                 'chain: for handler in self.handlers() {
-                    if handler.handle(&packet, &self.channels()) && !handler.chaining() {
-                        break 'chain;
+                    if handler.handle(&packet, self.channels()) {
+                        claimed = true;
+
+                        if !handler.chaining() {
+                            break 'chain;
+                        }
                     }
                 }
 
+                if !claimed {
+                    self._discover(&packet, buf);
+                }
+
                 if last_pkt.is_some() && !last_pkt.as_ref().unwrap().is_correct_response(&packet) {
                     eprintln!("Response drop, retry send -> {:?}", &last_pkt);
                     return false;
                 }
 
+                if let Some(sent) = last_pkt.take() {
+                    self.channels().commands.responded(self.name(), &sent.to_vec());
+                }
+
                 return true;
             },
             Err(e) => {
@@ -212,6 +559,7 @@ pub trait ISerial<T: SerialPacket> {
                     eprintln!("Error detected, retry send -> {:?}", &last_pkt);
                     return false;
                 }
+                *last_pkt = None;
                 return true;
             }
         }
@@ -221,11 +569,13 @@ pub trait ISerial<T: SerialPacket> {
 }
 
 impl ISerial<HyundaiPacket> for Serial<HyundaiPacket> {
+    fn name(&self) -> &str { &self.name }
     fn path(&self) -> &String { &self.path }
     fn baud_rate(&self) -> u32 { self.baud_rate }
     fn handlers(&self) -> &Vec<Box<dyn PacketHandler<HyundaiPacket> + Send>> { &self.handlers }
     fn channels(&self) -> &Channels<HyundaiPacket> { &self.channels }
     fn rx(&self) -> &Receiver<HyundaiPacket> { &self.rx }
+    fn replies(&self) -> &Receiver<HyundaiPacket> { &self.replies }
 
     fn prefix(&self) -> &'static [u8] { HyundaiPacket::PREFIX }
     fn suffix(&self) -> &'static [u8] { HyundaiPacket::SUFFIX }
@@ -239,5 +589,82 @@ impl ISerial<HyundaiPacket> for Serial<HyundaiPacket> {
     }
 
     fn various(&self) -> bool { self.print_various }
+    fn capture(&self) -> &Option<Arc<Mutex<CaptureWriter>>> { &self.capture }
+    fn discovery(&self) -> &Option<DiscoveryCounts> { &self.discovery }
+    fn echo_window(&self) -> &Duration { &self.echo_window }
+    fn transmitted(&self) -> &TransmittedFrames { &self.transmitted }
+    fn stats(&self) -> &Arc<SerialStats> { &self.stats }
+    fn read_only(&self) -> bool { self.read_only }
+    fn relay(&self) -> &Option<Relay> { &self.relay }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::{mqtt::recording::RecordingLink, things::{Thing, floor_heating::FloorHeating}};
+
+    use super::*;
+
+    const POLL: &[u8] = &[0xF7, 0x0B, 0x01, 0x18, 0x01, 0x45, 0x10, 0x00, 0x00, 0xB1, 0xEE];
+
+    fn serial(link: &RecordingLink, echo_window: Duration) -> Serial<HyundaiPacket> {
+        Serial::<HyundaiPacket>::new(
+            "primary",
+            String::new(),
+            vec![FloorHeating::<HyundaiPacket>::new().handler()],
+            link.link_tx(),
+            CommandTracker::new(link.link_tx(), false),
+            StateStore::new(link.link_tx()),
+            SerialOptions { echo_window, ..Default::default() }
+        )
+    }
+
+    #[test]
+    fn echo_is_dropped_once_within_the_window() {
+        let serial = serial(&RecordingLink::default(), Duration::from_millis(200));
+
+        serial._transmitted(POLL);
+
+        assert!(!serial._is_echo(&POLL[..10]));
+        assert!(serial._is_echo(POLL));
+        assert!(!serial._is_echo(POLL)); // Repeated by the wallpad
+    }
+
+    #[test]
+    fn echo_after_the_window_is_kept() {
+        let serial = serial(&RecordingLink::default(), Duration::from_millis(50));
+
+        serial._transmitted(POLL);
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(!serial._is_echo(POLL));
+    }
+
+    #[test]
+    fn zero_window_keeps_every_frame() {
+        let serial = serial(&RecordingLink::default(), Duration::ZERO);
+
+        serial._transmitted(POLL);
+
+        assert!(!serial._is_echo(POLL));
+    }
+
+    #[test]
+    fn echo_never_reaches_the_handlers() {
+        let link = RecordingLink::default();
+        let serial = serial(&link, Duration::from_millis(200));
+        let response = HyundaiPacket::new(0x18, packet::Command::Response, 0x46, 0x11, vec![0x00, 0x01, 0x14, 0x17]).to_vec();
+
+        serial._transmitted(&response);
+        assert!(serial._handle(&response, &mut None));
+
+        assert_eq!(serial.stats.echo_drops.load(Ordering::Relaxed), 1);
+        assert!(link.published.lock().unwrap().is_empty());
+
+        serial._handle(&response, &mut None);
+
+        assert_eq!(link.get("heating/0/temp/target").as_deref(), Some("23"));
+    }
+}
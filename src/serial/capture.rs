@@ -0,0 +1,260 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}, fmt::Display, str::FromStr, path::{Path, PathBuf}};
+
+use crate::utils;
+
+/// Result recorded for received bytes that do not start a frame and never reach the handlers.
+pub const UNFRAMED: &str = "Unframed";
+
+/// Result recorded for our own transmissions read back by the adapter.
+pub const ECHO: &str = "Echo";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Rx => write!(f, "rx"),
+            Direction::Tx => write!(f, "tx"),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rx" => Ok(Self::Rx),
+            "tx" => Ok(Self::Tx),
+            _ => Err(())
+        }
+    }
+
+    type Err = ();
+}
+
+/// A single captured frame.
+///
+/// Line format: `{seconds}.{micros}\t{port}\t{rx|tx}\t{result}\t{raw bytes in hex}`, the monotonic time since the capture started,
+/// so the gaps between the frames hold even if the clock is set meanwhile.
+#[derive(Debug, Clone)]
+pub struct CaptureEntry {
+    pub timestamp: Duration,
+    pub port: String,
+    pub direction: Direction,
+    pub result: String,
+    pub raw: Vec<u8>
+}
+
+impl Display for CaptureEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{}.{:06}\t{}\t{}\t{}\t{}",
+            self.timestamp.as_secs(), self.timestamp.subsec_micros(),
+            self.port, self.direction, self.result, utils::to_hex(&self.raw)
+        )
+    }
+}
+
+impl FromStr for CaptureEntry {
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.trim_end().splitn(5, '\t');
+
+        let timestamp = fields.next().and_then(|v| {
+            let (secs, micros) = v.split_once('.').unwrap_or((v, "0"));
+
+            Some(Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(format!("{:0<6.6}", micros).parse().ok()?))
+        }).ok_or(())?;
+        let port = fields.next().ok_or(())?;
+        let direction = fields.next().ok_or(())?.parse::<Direction>()?;
+        let result = fields.next().ok_or(())?;
+        let raw = utils::from_hex(fields.next().unwrap_or_default()).ok_or(())?;
+
+        Ok(Self {
+            timestamp,
+            port: port.to_string(),
+            direction,
+            result: result.to_string(),
+            raw
+        })
+    }
+
+    type Err = ();
+}
+
+/// Writes every frame on the bus to a file, rotating it as `{path}.1`, `{path}.2`... once it exceeds `max_size` bytes.
+///
+/// Every run, and every file after a rotation, begins with `# started {seconds}.{micros}`, the time since the Unix epoch
+/// the capture started at. The lines starting with `#` are not frames.
+pub struct CaptureWriter {
+    path: PathBuf,
+    file: File,
+
+    max_size: u64,
+    max_files: usize,
+
+    written: u64,
+
+    started: Instant,
+    header: String
+}
+
+impl CaptureWriter {
+    pub fn open(path: &str, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let header = format!("# started {}.{:06}\n", wall.as_secs(), wall.subsec_micros());
+
+        let mut capture = Self { path, file, max_size, max_files, written, started: Instant::now(), header };
+        capture.write_line(&capture.header.clone());
+
+        Ok(capture)
+    }
+
+    pub fn write(&mut self, port: &str, direction: Direction, result: &str, raw: &[u8]) {
+        let entry = CaptureEntry {
+            timestamp: self.started.elapsed(),
+            port: port.to_string(),
+            direction,
+            result: result.to_string(),
+            raw: raw.to_vec()
+        };
+
+        self.write_line(&format!("{}\n", entry));
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.max_size > 0 && self.written + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("Capture rotation failed {:?}", e);
+            }
+        }
+
+        match self.file.write_all(line.as_bytes()) {
+            Ok(_) => self.written += line.len() as u64,
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = Self::rotated_path(&self.path, i);
+
+                if from.exists() {
+                    fs::rename(&from, Self::rotated_path(&self.path, i + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, Self::rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.file.write_all(self.header.as_bytes())?;
+        self.written = self.header.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotated_path(path: &Path, index: usize) -> PathBuf {
+        let mut p = path.as_os_str().to_owned();
+        p.push(format!(".{}", index));
+
+        PathBuf::from(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// A capture path of its own in the temporary directory, with the files of an earlier run removed
+    fn capture_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("uwb-capture-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join("bus.log")
+    }
+
+    fn entries(path: &Path) -> Vec<CaptureEntry> {
+        fs::read_to_string(path).unwrap().lines().filter_map(|line| line.parse::<CaptureEntry>().ok()).collect()
+    }
+
+    #[test]
+    fn entry_round_trips() {
+        let line = "12.000345\tprimary\ttx\tOk\tF7 0B 01 18 01 45 10 00 00 B1 EE";
+        let entry = line.parse::<CaptureEntry>().unwrap();
+
+        assert_eq!(entry.timestamp, Duration::from_micros(12_000_345));
+        assert_eq!((entry.port.as_str(), entry.direction, entry.result.as_str()), ("primary", Direction::Tx, "Ok"));
+        assert_eq!(entry.to_string(), line);
+        assert!("# started 1700000000.000000".parse::<CaptureEntry>().is_err());
+    }
+
+    #[test]
+    fn timestamps_are_monotonic_from_the_start() {
+        let path = capture_path("monotonic");
+        let mut capture = CaptureWriter::open(path.to_str().unwrap(), 0, 0).unwrap();
+
+        for _ in 0..3 {
+            capture.write("primary", Direction::Rx, "Ok", &[0xF7]);
+        }
+
+        let entries = entries(&path);
+
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].timestamp < Duration::from_secs(1));
+        assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert!(fs::read_to_string(&path).unwrap().starts_with("# started "));
+    }
+
+    #[test]
+    fn rotates_and_keeps_log_files() {
+        let path = capture_path("rotation");
+        let mut capture = CaptureWriter::open(path.to_str().unwrap(), 200, 2).unwrap();
+
+        for i in 0..20u8 {
+            capture.write("primary", Direction::Rx, "Ok", &[i; 8]);
+        }
+
+        let rotated = |i| CaptureWriter::rotated_path(&path, i);
+
+        assert!(rotated(1).exists() && rotated(2).exists());
+        assert!(!rotated(3).exists());
+
+        for file in [rotated(2), rotated(1), path.clone()] {
+            assert!(fs::metadata(&file).unwrap().len() <= 200);
+            assert!(fs::read_to_string(&file).unwrap().starts_with("# started "));
+        }
+
+        // The newest frames are in the current file, the older ones in the rotated files in order
+        let kept: Vec<CaptureEntry> = [rotated(2), rotated(1), path.clone()].iter().flat_map(|f| entries(f)).collect();
+
+        assert_eq!(kept.last().unwrap().raw, vec![19; 8]);
+        assert!(kept.windows(2).all(|w| w[0].raw[0] + 1 == w[1].raw[0]));
+        assert!(kept.len() < 20);
+    }
+
+    #[test]
+    fn truncates_without_log_files() {
+        let path = capture_path("truncate");
+        let mut capture = CaptureWriter::open(path.to_str().unwrap(), 200, 0).unwrap();
+
+        for i in 0..20u8 {
+            capture.write("primary", Direction::Rx, "Ok", &[i; 8]);
+        }
+
+        assert!(!CaptureWriter::rotated_path(&path, 1).exists());
+        assert_eq!(entries(&path).last().unwrap().raw, vec![19; 8]);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{sync::{Arc, mpsc::{self, Sender, Receiver}}, str::FromStr};
+
+use super::packet::{SerialPacket, Command};
+
+/// Results of `Relay::forward`, also recorded in the capture for the frames a rule applied to
+pub const RELAYED: &str = "Relayed";
+pub const DROPPED: &str = "Dropped";
+pub const REWRITTEN: &str = "Rewritten";
+
+#[derive(Debug)]
+pub enum RuleParseError {
+    MissingArrow,
+    InvalidCondition(String),
+    InvalidAction(String)
+}
+
+/// Side of the bridge a frame is forwarded to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Wallpad,
+    Devices
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleAction {
+    Drop,
+    /// `(index, value)` of the data bytes to overwrite
+    Rewrite(Vec<(usize, u8)>)
+}
+
+/// A rule on the forwarded frames, written as `{conditions} -> {action}`.
+///
+/// Conditions are `to`, `device`, `sub`, `room`, `command` and `data[i]` separated by commas,
+/// the action is `drop` or the `data[i]` to overwrite. Numbers are in hex.
+/// (ex. `to=wallpad,device=2B,command=response,data[1]=81 -> data[1]=01`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub to: Option<Side>,
+    pub command: Option<Command>,
+    pub device_id: Option<u8>,
+    pub device_sub_id: Option<u8>,
+    pub room_id: Option<u8>,
+    pub data: Vec<(usize, u8)>,
+
+    pub action: RuleAction
+}
+
+fn parse_hex(value: &str) -> Option<u8> {
+    u8::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+fn parse_data_index(key: &str) -> Option<usize> {
+    key.strip_prefix("data[")?.strip_suffix(']')?.parse::<usize>().ok()
+}
+
+impl FromStr for Rule {
+    type Err = RuleParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (conditions, action) = s.split_once("->").ok_or(RuleParseError::MissingArrow)?;
+
+        let mut rule = Rule {
+            to: None,
+            command: None,
+            device_id: None,
+            device_sub_id: None,
+            room_id: None,
+            data: Vec::new(),
+            action: RuleAction::Drop
+        };
+
+        for condition in conditions.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let invalid = || RuleParseError::InvalidCondition(condition.to_string());
+            let (key, value) = condition.split_once('=').ok_or_else(invalid)?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "to" => rule.to = Some(match value {
+                    "wallpad" => Side::Wallpad,
+                    "devices" => Side::Devices,
+                    _ => return Err(invalid())
+                }),
+                "command" => rule.command = Some(value.parse::<Command>().map_err(|_| invalid())?),
+                "device" => rule.device_id = Some(parse_hex(value).ok_or_else(invalid)?),
+                "sub" => rule.device_sub_id = Some(parse_hex(value).ok_or_else(invalid)?),
+                "room" => rule.room_id = Some(parse_hex(value).ok_or_else(invalid)?),
+                _ => rule.data.push((parse_data_index(key).ok_or_else(invalid)?, parse_hex(value).ok_or_else(invalid)?))
+            }
+        }
+
+        let action = action.trim();
+
+        rule.action = if action == "drop" {
+            RuleAction::Drop
+        } else {
+            let invalid = || RuleParseError::InvalidAction(action.to_string());
+
+            RuleAction::Rewrite(action.split(',')
+                .map(|v| {
+                    let (key, value) = v.split_once('=').ok_or_else(invalid)?;
+
+                    Ok((parse_data_index(key.trim()).ok_or_else(invalid)?, parse_hex(value).ok_or_else(invalid)?))
+                })
+                .collect::<Result<Vec<(usize, u8)>, RuleParseError>>()?)
+        };
+
+        Ok(rule)
+    }
+}
+
+impl Rule {
+    pub fn matches<T: SerialPacket>(&self, to: Side, packet: &T) -> bool {
+        self.to.is_none_or(|v| v == to)
+            && self.command.is_none_or(|v| v == packet.command())
+            && self.device_id.is_none_or(|v| v == packet.device_id())
+            && self.device_sub_id.is_none_or(|v| v == packet.device_sub_id())
+            && self.room_id.is_none_or(|v| v == packet.room_id())
+            && self.data.iter().all(|(i, v)| packet.data().get(*i) == Some(v))
+    }
+}
+
+/// One end of the passthrough between the wallpad and the devices, forwarding the frames read on its port to the other.
+pub struct Relay {
+    /// Side the frames read on this port are forwarded to
+    pub to: Side,
+    pub rules: Arc<Vec<Rule>>,
+
+    peer_tx: Sender<Vec<u8>>,
+    pub rx: Receiver<Vec<u8>>
+}
+
+impl Relay {
+    /// Relays of the device side (primary) and the wallpad side (secondary) ports
+    pub fn pair(rules: Vec<Rule>) -> (Self, Self) {
+        let rules = Arc::new(rules);
+
+        let (devices_tx, devices_rx) = mpsc::channel();
+        let (wallpad_tx, wallpad_rx) = mpsc::channel();
+
+        (
+            Self { to: Side::Wallpad, rules: rules.clone(), peer_tx: wallpad_tx, rx: devices_rx },
+            Self { to: Side::Devices, rules, peer_tx: devices_tx, rx: wallpad_rx }
+        )
+    }
+
+    /// Forwards a frame read on this port after the rules, returning how it was forwarded.
+    /// Frames which could not be parsed are forwarded as they are.
+    pub fn forward<T: SerialPacket>(&self, packet: Option<&T>, raw: &[u8]) -> &'static str {
+        let rule = packet.and_then(|p| self.rules.iter().find(|r| r.matches(self.to, p)));
+
+        let (frame, result) = match (packet, rule) {
+            (_, Some(Rule { action: RuleAction::Drop, .. })) => return DROPPED,
+            (Some(packet), Some(Rule { action: RuleAction::Rewrite(data), .. })) => {
+                let mut packet = packet.clone();
+
+                for (i, v) in data {
+                    if let Some(byte) = packet.mut_data().get_mut(*i) {
+                        *byte = *v;
+                    }
+                }
+
+                (packet.to_vec(), REWRITTEN) // Checksums are calculated again
+            },
+            _ => (raw.to_vec(), RELAYED)
+        };
+
+        if let Err(e) = self.peer_tx.send(frame) {
+            eprintln!("{:?}", e);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serial::packet::hyundai::HyundaiPacket;
+
+    use super::*;
+
+    #[test]
+    fn rule_parses_conditions_and_rewrite() {
+        let rule = "to=wallpad,device=2B,sub=0x40,room=11,command=response,data[1]=81 -> data[1]=01, data[2]=FF".parse::<Rule>().unwrap();
+
+        assert_eq!(rule, Rule {
+            to: Some(Side::Wallpad),
+            command: Some(Command::Response),
+            device_id: Some(0x2B),
+            device_sub_id: Some(0x40),
+            room_id: Some(0x11),
+            data: vec![(1, 0x81)],
+            action: RuleAction::Rewrite(vec![(1, 0x01), (2, 0xFF)])
+        });
+    }
+
+    #[test]
+    fn rule_parses_drop_without_conditions() {
+        let rule = " -> drop".parse::<Rule>().unwrap();
+
+        assert_eq!((rule.to, rule.device_id, rule.data.len(), rule.action), (None, None, 0, RuleAction::Drop));
+    }
+
+    #[test]
+    fn rule_rejects_invalid_forms() {
+        assert!(matches!("device=2B drop".parse::<Rule>(), Err(RuleParseError::MissingArrow)));
+        assert!(matches!("to=nowhere -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("device=2G -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("device=100 -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("device -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("data[x]=01 -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("command=shout -> drop".parse::<Rule>(), Err(RuleParseError::InvalidCondition(_))));
+        assert!(matches!("device=2B -> keep".parse::<Rule>(), Err(RuleParseError::InvalidAction(_))));
+        assert!(matches!("device=2B -> data[1]".parse::<Rule>(), Err(RuleParseError::InvalidAction(_))));
+        assert!(matches!("device=2B -> data[1]=01,".parse::<Rule>(), Err(RuleParseError::InvalidAction(_))));
+    }
+
+    #[test]
+    fn relay_applies_first_matching_rule() {
+        let rules = vec![
+            "to=wallpad,device=2B,data[0]=01 -> data[0]=00".parse::<Rule>().unwrap(),
+            "to=wallpad,device=19 -> drop".parse::<Rule>().unwrap()
+        ];
+        let (devices, wallpad) = Relay::pair(rules);
+
+        let ventilator = HyundaiPacket::new(0x2B, Command::Response, 0x40, 0x11, vec![0x01, 0x02]);
+        let light = HyundaiPacket::new(0x19, Command::Response, 0x40, 0x11, vec![0x01]);
+        let heating = HyundaiPacket::new(0x18, Command::Response, 0x45, 0x11, vec![0x01]);
+
+        assert_eq!(devices.forward(Some(&ventilator), &ventilator.to_vec()), REWRITTEN);
+        assert_eq!(devices.forward(Some(&light), &light.to_vec()), DROPPED);
+        assert_eq!(devices.forward(Some(&heating), &heating.to_vec()), RELAYED);
+        assert_eq!(devices.forward::<HyundaiPacket>(None, &[0xF7, 0x00]), RELAYED);
+
+        let forwarded: Vec<Vec<u8>> = wallpad.rx.try_iter().collect();
+        let rewritten = HyundaiPacket::new(0x2B, Command::Response, 0x40, 0x11, vec![0x00, 0x02]);
+
+        assert_eq!(forwarded, vec![rewritten.to_vec(), heating.to_vec(), vec![0xF7, 0x00]]);
+
+        // Rules on the frames to the wallpad leave the other direction as it is
+        assert_eq!(wallpad.forward(Some(&light), &light.to_vec()), RELAYED);
+    }
+}
@@ -9,6 +9,7 @@ pub mod hyundai;
 pub trait SerialPacket: Clone + Debug + Send {
     fn to_vec(&self) -> Vec<u8>;
     fn parse(buf: &[u8]) -> Result<Self, PacketParseError> where Self: Sized;
+    fn create(device_id: u8, command: Command, device_sub_id: u8, room_id: u8, data: Vec<u8>) -> Self where Self: Sized;
 
     fn data(&self) -> &Vec<u8>;
     fn mut_data(&mut self) -> &mut Vec<u8>;
@@ -16,17 +17,21 @@ pub trait SerialPacket: Clone + Debug + Send {
     fn manufacturer() -> Manufacturer where Self: Sized;
 
     fn command(&self) -> Command;
+    fn device_id(&self) -> u8;
+    fn device_sub_id(&self) -> u8;
+    fn room_id(&self) -> u8;
 
     fn is_correct_response(&self, response: &Self) -> bool;
 
     fn length_from_buffer(buf: &[u8]) -> Option<usize>;
     fn baud_rate() -> u32 where Self: Sized;
+    /// Longest data a frame can carry
+    fn max_data_len() -> usize where Self: Sized;
 }
 
 pub trait PacketHandler<T: SerialPacket + ?Sized> {
     fn handle(&self, packet: &T, channels: &Channels<T>) -> bool;
     fn chaining(&self) -> bool;
-    fn is_primary(&self) -> bool;
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -36,6 +41,19 @@ pub enum Command {
     Response = 0x03
 }
 
+impl FromStr for Command {
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "request" => Ok(Self::Request),
+            "modify" => Ok(Self::Modify),
+            "response" => Ok(Self::Response),
+            _ => Err(())
+        }
+    }
+
+    type Err = ();
+}
+
 #[derive(Debug)]
 pub enum PacketParseError {
     BufferLengthTooSmall,
@@ -104,6 +104,10 @@ impl SerialPacket for HyundaiPacket {
         })
     }
 
+    fn create(device_id: u8, command: Command, device_sub_id: u8, room_id: u8, data: Vec<u8>) -> Self {
+        Self::new(device_id, command, device_sub_id, room_id, data)
+    }
+
     fn data(&self) -> &Vec<u8> {
         &self.data
     }
@@ -118,6 +122,18 @@ impl SerialPacket for HyundaiPacket {
         self.command
     }
 
+    fn device_id(&self) -> u8 {
+        self.device_id
+    }
+
+    fn device_sub_id(&self) -> u8 {
+        self.device_sub_id
+    }
+
+    fn room_id(&self) -> u8 {
+        self.room_id
+    }
+
     fn is_correct_response(&self, response: &Self) -> bool {
         self.command == Command::Response || (response.command == Command::Response && self.device_id == response.device_id && self.device_sub_id == response.device_sub_id && self.room_id == response.room_id)
     }
@@ -130,6 +146,10 @@ impl SerialPacket for HyundaiPacket {
     fn baud_rate() -> u32 where Self: Sized {
         9600
     }
+
+    fn max_data_len() -> usize where Self: Sized {
+        u8::MAX as usize - 9 // The length of the frame is a byte
+    }
 }
 
 
@@ -139,7 +159,6 @@ pub struct HyundaiPacketHandler {
     pub room_id: Option<u8>,
 
     pub callback: Box<fn(&HyundaiPacket, &Channels<HyundaiPacket>)>,
-    pub is_primary: bool,
     pub chaining: bool
 }
 
@@ -158,8 +177,4 @@ impl PacketHandler<HyundaiPacket> for HyundaiPacketHandler {
     }
 
     fn chaining(&self) -> bool { self.chaining }
-
-    fn is_primary(&self) -> bool {
-        self.is_primary
-    }
 }
@@ -0,0 +1,204 @@
+use std::{fmt::Display, str::FromStr};
+
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+#[derive(Debug)]
+pub enum PortError {
+    InvalidSelector(String),
+    NotFound(PortSelector),
+    Ambiguous(PortSelector, Vec<String>),
+    Serial(serialport::Error)
+}
+
+/// A serial port given by its path, or by the USB adapter as `usb:{vid}:{pid}[:{serial_number}]`.
+/// Omitted or `*` fields of a USB selector match any value. (ex. `usb:1a86:7523`, `usb:*:*:A10KDX3P`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortSelector {
+    Path(String),
+    Usb {
+        vid: Option<u16>,
+        pid: Option<u16>,
+        serial_number: Option<String>
+    }
+}
+
+impl FromStr for PortSelector {
+    type Err = PortError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = match s.strip_prefix("usb:") {
+            Some(v) => v.split(':').collect::<Vec<&str>>(),
+            None => return Ok(PortSelector::Path(s.to_string()))
+        };
+
+        if fields.len() > 3 {
+            return Err(PortError::InvalidSelector(s.to_string()));
+        }
+
+        let field = |i: usize| fields.get(i).copied().filter(|v| !v.is_empty() && *v != "*");
+
+        let id = |i: usize| match field(i) {
+            Some(v) => u16::from_str_radix(v.trim_start_matches("0x"), 16)
+                .map(Some)
+                .map_err(|_| PortError::InvalidSelector(s.to_string())),
+            None => Ok(None)
+        };
+
+        Ok(PortSelector::Usb {
+            vid: id(0)?,
+            pid: id(1)?,
+            serial_number: field(2).map(|v| v.to_string())
+        })
+    }
+}
+
+impl Display for PortSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = |v: &Option<u16>| v.map(|v| format!("{:04x}", v)).unwrap_or("*".to_string());
+
+        match self {
+            PortSelector::Path(path) => write!(f, "{}", path),
+            PortSelector::Usb { vid, pid, serial_number } => write!(f, "usb:{}:{}:{}", id(vid), id(pid), serial_number.as_deref().unwrap_or("*"))
+        }
+    }
+}
+
+impl PortSelector {
+    /// Finds the current path of the port. USB selectors must match exactly one adapter.
+    pub fn resolve(&self) -> Result<String, PortError> {
+        match self {
+            PortSelector::Path(path) => Ok(path.clone()),
+            PortSelector::Usb { .. } => self.select(serialport::available_ports().map_err(PortError::Serial)?)
+        }
+    }
+
+    /// Picks the only port among `ports` matching the selector
+    fn select(&self, ports: Vec<SerialPortInfo>) -> Result<String, PortError> {
+        let ports: Vec<String> = ports.into_iter()
+            .filter(|p| match &p.port_type {
+                SerialPortType::UsbPort(info) => self.matches(info),
+                _ => false
+            })
+            .map(|p| p.port_name)
+            .collect();
+
+        match ports.len() {
+            0 => Err(PortError::NotFound(self.clone())),
+            1 => Ok(ports[0].clone()),
+            _ => Err(PortError::Ambiguous(self.clone(), ports))
+        }
+    }
+
+    fn matches(&self, info: &UsbPortInfo) -> bool {
+        match self {
+            PortSelector::Path(_) => false,
+            PortSelector::Usb { vid, pid, serial_number } => {
+                vid.is_none_or(|v| v == info.vid)
+                    && pid.is_none_or(|v| v == info.pid)
+                    && serial_number.as_ref().is_none_or(|v| info.serial_number.as_ref() == Some(v))
+            }
+        }
+    }
+}
+
+/// Lists the USB serial adapters with the selector of each.
+pub fn usb_ports() -> Vec<(String, PortSelector)> {
+    match serialport::available_ports() {
+        Ok(ports) => ports.into_iter()
+            .filter_map(|p| match p.port_type {
+                SerialPortType::UsbPort(info) => Some((p.port_name, PortSelector::Usb {
+                    vid: Some(info.vid),
+                    pid: Some(info.pid),
+                    serial_number: info.serial_number
+                })),
+                _ => None
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb(name: &str, vid: u16, pid: u16, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: serial_number.map(|v| v.to_string()),
+                manufacturer: None,
+                product: None
+            })
+        }
+    }
+
+    fn ports() -> Vec<SerialPortInfo> {
+        vec![
+            SerialPortInfo { port_name: "/dev/ttyS0".to_string(), port_type: SerialPortType::Unknown },
+            usb("/dev/ttyUSB0", 0x1a86, 0x7523, Some("A10KDX3P")),
+            usb("/dev/ttyUSB1", 0x1a86, 0x7523, Some("A10KDX3Q")),
+            usb("/dev/ttyUSB2", 0x0403, 0x6001, None)
+        ]
+    }
+
+    fn selector(s: &str) -> PortSelector {
+        s.parse::<PortSelector>().unwrap()
+    }
+
+    #[test]
+    fn parses_usb_selectors() {
+        assert_eq!(selector("usb:1a86:7523"), PortSelector::Usb { vid: Some(0x1a86), pid: Some(0x7523), serial_number: None });
+        assert_eq!(selector("usb:0x1A86:7523:A10KDX3Q"), PortSelector::Usb { vid: Some(0x1a86), pid: Some(0x7523), serial_number: Some("A10KDX3Q".to_string()) });
+        assert_eq!(selector("usb:*:*:A10KDX3P"), PortSelector::Usb { vid: None, pid: None, serial_number: Some("A10KDX3P".to_string()) });
+        assert_eq!(selector("usb:1a86"), PortSelector::Usb { vid: Some(0x1a86), pid: None, serial_number: None });
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(selector("/dev/ttyUSB0"), PortSelector::Path("/dev/ttyUSB0".to_string()));
+        assert_eq!(selector("COM2"), PortSelector::Path("COM2".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_usb_selectors() {
+        for s in ["usb:1a8g:7523", "usb:1a86:75230", "usb:1a86:7523:A10KDX3Q:1", "usb:-1:7523"] {
+            assert!(matches!(s.parse::<PortSelector>(), Err(PortError::InvalidSelector(v)) if v == s), "{}", s);
+        }
+    }
+
+    #[test]
+    fn displays_as_parsed() {
+        for s in ["usb:1a86:7523:A10KDX3Q", "usb:*:*:A10KDX3P", "/dev/ttyUSB0"] {
+            assert_eq!(selector(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn selects_the_only_matching_adapter() {
+        assert_eq!(selector("usb:1a86:7523:A10KDX3Q").select(ports()).unwrap(), "/dev/ttyUSB1");
+        assert_eq!(selector("usb:*:*:A10KDX3P").select(ports()).unwrap(), "/dev/ttyUSB0");
+        assert_eq!(selector("usb:0403:6001").select(ports()).unwrap(), "/dev/ttyUSB2");
+    }
+
+    #[test]
+    fn rejects_missing_and_ambiguous_adapters() {
+        assert!(matches!(selector("usb:1a86:7524").select(ports()), Err(PortError::NotFound(_))));
+        assert!(matches!(selector("usb:0403:6001:A10KDX3P").select(ports()), Err(PortError::NotFound(_))));
+
+        match selector("usb:1a86:7523").select(ports()) {
+            Err(PortError::Ambiguous(_, ports)) => assert_eq!(ports, vec!["/dev/ttyUSB0", "/dev/ttyUSB1"]),
+            result => panic!("expected an ambiguous selector, {:?}", result)
+        }
+    }
+
+    #[test]
+    fn path_never_matches_an_adapter() {
+        assert!(matches!(selector("/dev/ttyUSB0").select(ports()), Err(PortError::NotFound(_))));
+    }
+}
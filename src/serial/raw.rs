@@ -0,0 +1,192 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde_json::Value;
+
+use crate::{things::Channels, utils};
+
+use super::packet::{SerialPacket, PacketHandler, Command, PacketParseError};
+
+/// Pending packets are forgotten when no response arrives in this time.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum RawPacketError {
+    InvalidHex,
+    InvalidJson,
+    MissingField(&'static str),
+    /// The ids and the data must be given in hex strings
+    InvalidField(&'static str),
+    Parse(PacketParseError),
+    ChannelClosed
+}
+
+/// Sends packets given over `raw/{port}/send` and publishes the responses on `raw/{port}/response`.
+#[derive(Clone)]
+pub struct RawInjector<T: SerialPacket> {
+    pub port: String,
+    pending: Arc<Mutex<Vec<(T, Instant)>>>
+}
+
+impl<T: SerialPacket + 'static> RawInjector<T> {
+    pub fn new(port: &str) -> Self {
+        Self { port: port.to_string(), pending: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn send_topic(&self) -> String {
+        format!("raw/{}/send", self.port)
+    }
+
+    pub fn response_topic(&self) -> String {
+        format!("raw/{}/response", self.port)
+    }
+
+    pub fn handler(&self) -> Box<dyn PacketHandler<T> + Send> {
+        Box::new(RawPacketHandler { injector: self.clone() })
+    }
+
+    /// Accepts a whole frame in hex (`F7 0B 01 ...`) or the fields in hex, like the topics of the discovery
+    /// (ex. `{"device_id": "43", "command": "request", "sub_id": "1F", "room_id": "11", "data": "00 00"}`).
+    pub fn send(&self, payload: &[u8], channels: &Channels<T>) {
+        let result = Self::packet_from_payload(payload).and_then(|packet| {
+            match self.pending.lock() {
+                Ok(mut pending) => pending.push((packet.clone(), Instant::now())),
+                Err(e) => eprintln!("{:?}", e)
+            }
+
+            channels.serial_tx.send(packet).map_err(|_| RawPacketError::ChannelClosed)
+        });
+
+        if let Err(e) = result {
+            eprintln!("Raw packet {:?}", e);
+
+            self.publish(channels, serde_json::json!({ "error": format!("{:?}", e) }));
+        }
+    }
+
+    fn packet_from_payload(payload: &[u8]) -> Result<T, RawPacketError> {
+        let payload = String::from_utf8_lossy(payload);
+        let payload = payload.trim();
+
+        if !payload.starts_with('{') {
+            let buf = utils::from_hex(payload).ok_or(RawPacketError::InvalidHex)?;
+
+            return T::parse(&buf).map_err(RawPacketError::Parse);
+        }
+
+        let json: Value = serde_json::from_str(payload).map_err(|_| RawPacketError::InvalidJson)?;
+
+        let byte = |key: &'static str| -> Result<u8, RawPacketError> {
+            match &json[key] {
+                Value::String(v) => u8::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| RawPacketError::InvalidField(key)),
+                Value::Null => Err(RawPacketError::MissingField(key)),
+                _ => Err(RawPacketError::InvalidField(key))
+            }
+        };
+
+        let command = match &json["command"] {
+            Value::String(v) => v.parse::<Command>().ok(),
+            Value::Null => Some(Command::Request),
+            _ => None
+        }.ok_or(RawPacketError::MissingField("command"))?;
+
+        let data = match &json["data"] {
+            Value::String(v) => utils::from_hex(v),
+            Value::Null => Some(Vec::new()),
+            _ => None
+        }.filter(|v| v.len() <= T::max_data_len()).ok_or(RawPacketError::InvalidField("data"))?;
+
+        Ok(T::create(byte("device_id")?, command, byte("sub_id")?, byte("room_id")?, data))
+    }
+
+    fn publish(&self, channels: &Channels<T>, payload: Value) {
+        if let Some(mut link_tx) = utils::link_tx_lock(&channels.link_tx) {
+            if let Err(e) = link_tx.publish(self.response_topic(), payload.to_string()) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+pub struct RawPacketHandler<T: SerialPacket> {
+    injector: RawInjector<T>
+}
+
+impl<T: SerialPacket + 'static> PacketHandler<T> for RawPacketHandler<T> {
+    fn handle(&self, packet: &T, channels: &Channels<T>) -> bool {
+        let matched = match self.injector.pending.lock() {
+            Ok(mut pending) => {
+                pending.retain(|(_, sent)| sent.elapsed() < RESPONSE_TIMEOUT);
+
+                match pending.iter().position(|(p, _)| p.command() != Command::Response && p.is_correct_response(packet)) {
+                    Some(i) => {
+                        pending.remove(i);
+                        true
+                    },
+                    None => false
+                }
+            },
+            Err(e) => {
+                eprintln!("{:?}", e);
+                false
+            }
+        };
+
+        if matched {
+            self.injector.publish(channels, serde_json::json!({
+                "device_id": format!("{:02X}", packet.device_id()),
+                "command": format!("{:?}", packet.command()),
+                "sub_id": format!("{:02X}", packet.device_sub_id()),
+                "room_id": format!("{:02X}", packet.room_id()),
+                "data": utils::to_hex(packet.data()),
+                "raw": utils::to_hex(&packet.to_vec())
+            }));
+        }
+
+        matched
+    }
+
+    fn chaining(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serial::packet::hyundai::HyundaiPacket;
+
+    use super::*;
+
+    fn packet(payload: &str) -> Result<HyundaiPacket, RawPacketError> {
+        RawInjector::<HyundaiPacket>::packet_from_payload(payload.as_bytes())
+    }
+
+    #[test]
+    fn fields_are_taken_in_hex() {
+        let packet = packet(r#"{"device_id": "43", "command": "request", "sub_id": "1F", "room_id": "0x11", "data": "00 01"}"#).unwrap();
+
+        assert_eq!((packet.device_id, packet.command, packet.device_sub_id, packet.room_id), (0x43, Command::Request, 0x1F, 0x11));
+        assert_eq!(packet.data, vec![0x00, 0x01]);
+    }
+
+    #[test]
+    fn frame_is_taken_in_hex() {
+        let packet = packet("F7 0B 01 18 01 45 10 00 00 B1 EE").unwrap();
+
+        assert_eq!((packet.device_id, packet.device_sub_id, packet.room_id), (0x18, 0x45, 0x10));
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        assert!(matches!(packet(r#"{"device_id": 67, "sub_id": "1F", "room_id": "11"}"#), Err(RawPacketError::InvalidField("device_id"))));
+        assert!(matches!(packet(r#"{"device_id": "43", "room_id": "11"}"#), Err(RawPacketError::MissingField("sub_id"))));
+        assert!(matches!(packet(r#"{"device_id": "43", "sub_id": "1F", "room_id": "11", "data": [0]}"#), Err(RawPacketError::InvalidField("data"))));
+        assert!(matches!(packet("F7 0B 01 1"), Err(RawPacketError::InvalidHex)));
+    }
+
+    #[test]
+    fn data_longer_than_a_frame_is_rejected() {
+        let fields = |len: usize| format!(r#"{{"device_id": "43", "sub_id": "1F", "room_id": "11", "data": "{}"}}"#, "00 ".repeat(len));
+
+        assert_eq!(packet(&fields(246)).unwrap().to_vec()[1], 0xFF);
+        assert!(matches!(packet(&fields(247)), Err(RawPacketError::InvalidField("data"))));
+        assert!(matches!(packet(&fields(1000)), Err(RawPacketError::InvalidField("data"))));
+    }
+}
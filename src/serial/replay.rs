@@ -0,0 +1,144 @@
+use std::{fs::File, io::{self, BufRead, BufReader}, sync::{Arc, Mutex}};
+
+use super::{capture::CaptureEntry, packet::SerialPacket};
+
+/// Reads all frames from a capture file, skipping lines that cannot be parsed.
+pub fn read_capture(path: &str) -> io::Result<Vec<CaptureEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for line in reader.lines() {
+        if let Ok(entry) = line?.parse::<CaptureEntry>() {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Collects the packets a replayed serial would have written to the port.
+#[derive(Clone)]
+pub struct ReplaySink<T: SerialPacket> {
+    written: Arc<Mutex<Vec<T>>>
+}
+
+impl<T: SerialPacket> ReplaySink<T> {
+    pub fn new() -> Self {
+        Self { written: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn push(&self, packet: T) {
+        match self.written.lock() {
+            Ok(mut written) => written.push(packet),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    pub fn written(&self) -> Vec<T> {
+        match self.written.lock() {
+            Ok(written) => written.clone(),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        match self.written.lock() {
+            Ok(mut written) => written.clear(),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+}
+
+impl<T: SerialPacket> Default for ReplaySink<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttd::protocol::Publish;
+
+    use crate::{mqtt::recording::RecordingLink, serial::{Serial, ISerial, SerialOptions, packet::{Command, hyundai::HyundaiPacket}}};
+    use crate::things::{Thing, command::CommandTracker, state::StateStore, router::Params, floor_heating::FloorHeating, living_room_light::LivingRoomLight, ventilator::Ventilator};
+
+    use super::*;
+
+    /// Polls of the wallpad and the responses of the heating, the lights and the ventilator, taken from the simulator
+    const CAPTURE: &str = "\
+0.496825\tprimary\trx\tOk\tF7 0B 01 18 01 45 10 00 00 B1 EE
+0.526985\tprimary\trx\tOk\tF7 16 01 18 04 45 10 00 04 13 17 04 13 17 04 13 17 04 13 17 A9 EE
+0.557121\tprimary\trx\tOk\tF7 0B 01 19 01 40 10 00 00 B5 EE
+0.587275\tprimary\trx\tOk\tF7 0C 01 19 04 40 10 00 02 02 B7 EE
+0.617421\tprimary\trx\tOk\tF7 0B 01 2B 01 40 11 00 00 86 EE
+0.647561\tprimary\trx\tOk\tF7 0C 01 2B 04 40 11 00 02 01 87 EE
+0.700000\tsecondary\trx\tOk\tF7 0B 01 18 01 45 10 00 00 B1 EE
+";
+
+    fn serial(link: &RecordingLink) -> Serial<HyundaiPacket> {
+        let link_tx = link.link_tx();
+        let things = [FloorHeating::<HyundaiPacket>::new(), LivingRoomLight::<HyundaiPacket>::new(), Ventilator::<HyundaiPacket>::new()];
+
+        Serial::<HyundaiPacket>::new(
+            "primary",
+            String::new(),
+            things.iter().map(|t| t.handler()).collect(),
+            link_tx.clone(),
+            CommandTracker::new(link_tx.clone(), false),
+            StateStore::new(link_tx),
+            SerialOptions::default()
+        )
+    }
+
+    fn entries() -> Vec<CaptureEntry> {
+        CAPTURE.lines().map(|line| line.parse::<CaptureEntry>().unwrap()).collect()
+    }
+
+    #[test]
+    fn replay_publishes_states() {
+        let link = RecordingLink::default();
+        let sink = ReplaySink::new();
+
+        serial(&link).replay(&entries(), false, &sink);
+
+        assert_eq!(link.get("heating/0/power").as_deref(), Some("false"));
+        assert_eq!(link.get("heating/3/temp/current").as_deref(), Some("19"));
+        assert_eq!(link.get("heating/3/temp/target").as_deref(), Some("23"));
+        assert_eq!(link.get("light/0/01").as_deref(), Some("false"));
+        assert_eq!(link.get("ventilator/power").as_deref(), Some("false"));
+        assert_eq!(link.get("ventilator/mode").as_deref(), Some("Off"));
+        assert!(sink.written().is_empty());
+    }
+
+    #[test]
+    fn replay_skips_other_ports() {
+        let link = RecordingLink::default();
+        let sink = ReplaySink::new();
+
+        serial(&link).replay(&entries()[6..], false, &sink);
+
+        assert!(link.retained().is_empty());
+    }
+
+    #[test]
+    fn replay_writes_commands() {
+        let link = RecordingLink::default();
+        let sink = ReplaySink::new();
+        let serial = serial(&link);
+
+        let route = FloorHeating::<HyundaiPacket>::new().routes().into_iter().find(|r| r.pattern == "heating/{room:u8}/temp/set").unwrap();
+        let params = Params::from(vec![("room", "1".to_string())]);
+
+        route.handler(&serial.channels)(&params, &Publish::new("heating/1/temp/set", "25", false)).unwrap();
+
+        serial.replay(&entries(), false, &sink);
+
+        let written = sink.written();
+
+        assert_eq!(written.len(), 1);
+        assert_eq!((written[0].device_id, written[0].command, written[0].device_sub_id, written[0].room_id), (0x18, Command::Modify, 0x45, 0x12));
+    }
+}
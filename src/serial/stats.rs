@@ -0,0 +1,68 @@
+use std::{sync::{atomic::{AtomicU64, Ordering}, Mutex}, collections::BTreeMap, time::Instant};
+
+use serde_json::Value;
+
+use super::packet::PacketParseError;
+
+/// Counters of a serial port, shared with the threads reporting them.
+#[derive(Debug, Default)]
+pub struct SerialStats {
+    pub frames_received: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub retries: AtomicU64,
+    /// Packets dropped after `MAX_RETRIES` without a correct response
+    pub give_ups: AtomicU64,
+    pub echo_drops: AtomicU64,
+    /// Bytes thrown away while looking for the start of the next frame
+    pub discarded_bytes: AtomicU64,
+    pub reconnects: AtomicU64,
+
+    pub parse_errors: Mutex<BTreeMap<String, u64>>,
+    pub last_valid_frame: Mutex<Option<Instant>>
+}
+
+impl SerialStats {
+    pub fn increase(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self, error: &PacketParseError) {
+        match self.parse_errors.lock() {
+            Ok(mut errors) => *errors.entry(format!("{:?}", error)).or_default() += 1,
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    pub fn valid_frame(&self) {
+        match self.last_valid_frame.lock() {
+            Ok(mut last) => *last = Some(Instant::now()),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    /// Payload of `bridge/port/{name}/stats`
+    pub fn to_json(&self) -> Value {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        serde_json::json!({
+            "frames_received": get(&self.frames_received),
+            "frames_sent": get(&self.frames_sent),
+            "parse_errors": self.parse_errors.lock().map(|v| v.clone()).unwrap_or_default(),
+            "retries": get(&self.retries),
+            "give_ups": get(&self.give_ups),
+            "echo_drops": get(&self.echo_drops),
+            "discarded_bytes": get(&self.discarded_bytes),
+            "reconnects": get(&self.reconnects),
+            // Seconds since the last frame parsed without an error, null before the first one
+            "last_valid_frame": self.last_valid_frame.lock().ok().and_then(|v| v.map(|v| v.elapsed().as_millis() as f64 / 1000.0))
+        })
+    }
+
+    pub fn topic(port: &str) -> String {
+        format!("bridge/port/{}/stats", port)
+    }
+}
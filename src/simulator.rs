@@ -0,0 +1,107 @@
+use std::{io::{self, Read, Write}, time::{Duration, Instant}, thread};
+
+use crate::{serial::packet::SerialPacket, models::DeviceModel};
+
+/// Silence between frames, as devices on a real bus answer one frame at a time.
+const FRAME_GAP: Duration = Duration::from_millis(30);
+
+/// Emulates the devices of a wallpad bus on the given port.
+pub struct Simulator<T: SerialPacket> {
+    pub devices: Vec<Box<dyn DeviceModel<T> + Send>>,
+
+    pub poll_interval: Option<Duration>,
+    /// Read back every received frame, as some RS-485 adapters do
+    pub echo: bool,
+    pub print_various: bool
+}
+
+impl<T: SerialPacket> Simulator<T> {
+    pub fn new(devices: Vec<Box<dyn DeviceModel<T> + Send>>, poll_interval: Option<Duration>, echo: bool, print_various: bool) -> Self {
+        Self { devices, poll_interval, echo, print_various }
+    }
+
+    pub fn run<P: Read + Write + ?Sized>(&mut self, port: &mut P) -> io::Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut tmp: [u8; 1024] = [0; 1024];
+
+        let mut last_poll = Instant::now();
+
+        loop {
+            match port.read(&mut tmp) {
+                Ok(0) => (),
+                Ok(l) => {
+                    buf.extend_from_slice(&tmp[0..l]);
+
+                    while let Some(len) = T::length_from_buffer(&buf) {
+                        if len < 2 {
+                            buf.clear();
+                            break;
+                        }
+
+                        if buf.len() < len {
+                            break;
+                        }
+
+                        let frame: Vec<u8> = buf.drain(0..len).collect();
+
+                        match T::parse(&frame) {
+                            Ok(packet) => {
+                                if self.print_various {
+                                    println!("<- {:?}", packet);
+                                }
+
+                                if self.echo {
+                                    Self::write(port, &packet, self.print_various)?;
+                                }
+
+                                for response in self.respond(&packet) {
+                                    Self::write(port, &response, self.print_various)?;
+                                }
+                            },
+                            Err(e) => {
+                                eprintln!("Buffer {:?} {:?}", e, &frame);
+                                buf.clear();
+                            }
+                        }
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e)
+            }
+
+            if let Some(interval) = self.poll_interval {
+                if last_poll.elapsed() >= interval {
+                    last_poll = Instant::now();
+
+                    for request in self.devices.iter().flat_map(|d| d.polls()).collect::<Vec<T>>() {
+                        Self::write(port, &request, self.print_various)?;
+
+                        for response in self.respond(&request) {
+                            Self::write(port, &response, self.print_various)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn respond(&mut self, packet: &T) -> Vec<T> {
+        self.devices.iter_mut()
+            .filter(|d| d.device_id() == packet.device_id())
+            .filter_map(|d| d.respond(packet))
+            .collect()
+    }
+
+    fn write<P: Read + Write + ?Sized>(port: &mut P, packet: &T, print_various: bool) -> io::Result<()> {
+        if print_various {
+            println!("-> {:?}", packet);
+        }
+
+        port.write_all(&packet.to_vec())?;
+        port.flush()?;
+
+        thread::sleep(FRAME_GAP);
+
+        Ok(())
+    }
+}
@@ -1,29 +1,128 @@
-use std::sync::{Arc, Mutex, mpsc::Sender};
+use std::sync::{Arc, Mutex, mpsc::{self, Sender}};
 
-use rumqttd::{local::LinkTx, protocol::Publish};
+use crate::{serial::packet::{PacketHandler, SerialPacket, Manufacturer}, mqtt::MqttLink, utils};
 
-use crate::serial::packet::{PacketHandler, SerialPacket, Manufacturer};
-
-use self::{floor_heating::FloorHeating, living_room_light::LivingRoomLight, realtime_energy_meter::RealtimeEnergyMeter, ventilator::Ventilator};
+use self::{command::CommandTracker, state::StateStore, router::Route, refresh::Refresh, payload::PayloadStyle, discovery::DiscoveryConfig, homie::HomieNode, floor_heating::FloorHeating, living_room_light::LivingRoomLight, realtime_energy_meter::RealtimeEnergyMeter, ventilator::Ventilator};
 
 pub mod realtime_energy_meter;
 pub mod floor_heating;
 pub mod living_room_light;
 pub mod ventilator;
+pub mod command;
+pub mod virtual_device;
+pub mod discovery;
+pub mod homie;
+pub mod availability;
+pub mod document;
+pub mod state;
+pub mod router;
+pub mod payload;
+pub mod refresh;
 
 pub trait Thing<T: SerialPacket> {
     fn handler(&self) -> Box<dyn PacketHandler<T> + Send>;
-    fn topic_handler(&self) -> fn(&Publish, &Channels<T>, &Option<&Channels<T>>);
-    fn subscribe(&self, link_tx: &mut LinkTx);
+    /// Topics handled by this device, which are also subscribed to
+    fn routes(&self) -> Vec<Route<T>>;
 
-    fn task(&self) -> Option<fn(&Channels<T>, &Option<&Channels<T>>)>;
+    fn task(&self) -> Option<fn(&Channels<T>)>;
+    /// Requests of the wallpad polling this device, sent by the bridge as the bus master
+    fn polls(&self) -> Vec<T>;
+    /// Requests asking this device for all of its states, sent on `{device}/refresh`
+    fn refresh(&self) -> Vec<Refresh<T>>;
+    /// Home Assistant entities of this device
+    fn discovery(&self) -> Vec<DiscoveryConfig>;
+    /// Homie nodes of this device
+    fn homie(&self) -> Vec<HomieNode>;
     fn new() -> Box<dyn Thing<T> + Send> where Self: Sized;
 }
 
 #[derive(Clone)]
 pub struct Channels<T: SerialPacket> {
-    pub link_tx: Arc<Mutex<LinkTx>>,
-    pub serial_tx: Sender<T>
+    pub port: String,
+
+    pub link_tx: Arc<Mutex<Box<dyn MqttLink>>>,
+    pub serial_tx: Sender<T>,
+    /// Responses written right after the frame being handled, see `reply`
+    pub reply_tx: Sender<T>,
+
+    pub commands: CommandTracker,
+    pub states: StateStore,
+
+    pub read_only: bool,
+    /// Publish a JSON document with all states of each device on `{device}/state`
+    pub json_state: bool,
+    pub style: PayloadStyle,
+    /// The packets are held back by `staged` until the whole command is valid
+    pub staging: bool
+}
+
+impl<T: SerialPacket> Channels<T> {
+    /// Queues a packet on the serial port, as a part of the command being handled. Nothing is sent in the read-only mode.
+    pub fn send(&self, packet: T) {
+        if self.read_only {
+            return;
+        }
+
+        if !self.staging {
+            self.commands.queued(&self.port, packet.to_vec());
+        }
+
+        match self.serial_tx.send(packet) {
+            Ok(_) => (),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    /// Writes a response of a virtual device right after the frame it answers, instead of queuing it until the bus is silent,
+    /// as the wallpad waits for it only briefly. Nothing is sent in the read-only mode.
+    pub fn reply(&self, packet: T) {
+        if self.read_only {
+            return;
+        }
+
+        match self.reply_tx.send(packet) {
+            Ok(_) => (),
+            Err(e) => eprintln!("{:?}", e)
+        }
+    }
+
+    /// Handles a command made of several ones (ex. a JSON document), queuing their packets only if all of them succeed,
+    /// so a rejected command leaves the device as it is.
+    pub fn staged<F: FnOnce(&Channels<T>) -> Result<(), String>>(&self, handle: F) -> Result<(), String> {
+        let (serial_tx, staged) = mpsc::channel();
+
+        handle(&Channels { serial_tx, staging: true, ..self.clone() })?;
+
+        for packet in staged.try_iter() {
+            self.send(packet);
+        }
+
+        Ok(())
+    }
+
+    /// Publishes a state of the device as retained for the clients connecting later, only if it changed
+    pub fn publish<S: Into<String>, V: Into<String>>(&self, topic: S, payload: V) {
+        let (topic, payload) = (topic.into(), payload.into());
+
+        self.commands.observe(&topic, &payload);
+        self.states.publish(&topic, &payload);
+    }
+
+    /// Publishes all states of a device at once as retained on `{device}/state`, if the JSON documents are enabled
+    pub fn publish_document(&self, device: &str, state: serde_json::Value) {
+        if self.json_state {
+            self.publish(document::state_topic(device), state.to_string());
+        }
+    }
+
+    /// Publishes a command for another device, which is not retained so it is never applied again
+    pub fn publish_command<S: Into<String>, V: Into<String>>(&self, topic: S, payload: V) {
+        if let Some(mut link_tx) = utils::link_tx_lock(&self.link_tx) {
+            if let Err(e) = link_tx.publish(topic.into(), payload.into()) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
 }
 
 impl<T: SerialPacket> Clone for Box<dyn Thing<T> + Send> where Self: Thing<T> {
@@ -36,12 +135,7 @@ impl<T: SerialPacket> Clone for Box<dyn Thing<T> + Send> where Self: Thing<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct TopicHandler<T: SerialPacket> {
-    pub handle: fn(&Publish, &Channels<T>)
-}
-
-#[derive(clap::ValueEnum, Clone, Debug)]
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Feature {
     #[clap(name = "floor_heating")]
     FloorHeating,
@@ -68,19 +162,29 @@ impl Feature {
         }
     }
 
-    pub fn defaults<T: SerialPacket>() -> Vec<Box<dyn Thing<T> + Send>> 
-        where FloorHeating<T>: Thing<T>,
-              LivingRoomLight<T>: Thing<T>,
-              RealtimeEnergyMeter<T>: Thing<T>,
-              Ventilator<T>: Thing<T>,
-    {
-        match T::manufacturer() {
+    pub fn defaults(manufacturer: &Manufacturer) -> Vec<Feature> {
+        match manufacturer {
             Manufacturer::HyundaiHT => vec![
-                FloorHeating::<T>::new(),
-                Ventilator::<T>::new(),
-                LivingRoomLight::<T>::new(),
-                RealtimeEnergyMeter::<T>::new(),
+                Feature::FloorHeating,
+                Feature::Ventilator,
+                Feature::LivingRoomLights,
+                Feature::RealtimeEnergyMeter,
             ],
         }
     }
+
+    /// Name of the feature on the command line, also used in the topics of the bridge
+    pub fn name(&self) -> String {
+        use clap::ValueEnum;
+
+        self.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+    }
+
+    /// Port the device is usually wired to
+    pub fn default_port(&self) -> &'static str {
+        match self {
+            Feature::RealtimeEnergyMeter => "secondary",
+            _ => "primary"
+        }
+    }
 }
\ No newline at end of file
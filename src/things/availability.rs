@@ -0,0 +1,127 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use rumqttd::protocol::Publish;
+
+use crate::{serial::packet::{SerialPacket, PacketHandler, Command}, mqtt::MqttLink, utils};
+
+use super::Channels;
+
+/// `online` while the bridge is running, `offline` as the last will on an external broker
+pub const BRIDGE_TOPIC: &str = "bridge/availability";
+
+struct DeviceState {
+    name: String,
+    last_seen: Option<Instant>,
+    online: Option<bool>
+}
+
+/// Follows the responses of each device, reporting it `offline` on `bridge/availability/{name}`
+/// when it has not answered for the timeout, and `online` again on the next response.
+#[derive(Clone)]
+pub struct Availability {
+    link_tx: Arc<Mutex<Box<dyn MqttLink>>>,
+    devices: Arc<Mutex<Vec<DeviceState>>>,
+
+    timeout: Duration,
+    started: Instant
+}
+
+impl Availability {
+    pub fn new(link_tx: Arc<Mutex<Box<dyn MqttLink>>>, timeout: Duration) -> Self {
+        Self { link_tx, devices: Arc::new(Mutex::new(Vec::new())), timeout, started: Instant::now() }
+    }
+
+    pub fn topic(name: &str) -> String {
+        format!("{}/{}", BRIDGE_TOPIC, name)
+    }
+
+    /// Publishes the availability of the bridge
+    pub fn bridge_online(link_tx: &Arc<Mutex<Box<dyn MqttLink>>>) {
+        if let Some(mut link_tx) = utils::link_tx_lock(link_tx) {
+            if let Err(e) = link_tx.send(Publish::new(BRIDGE_TOPIC, "online", true), None) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+
+    /// Wraps the packet handler of a device, marking it seen on its responses
+    pub fn handler<T: SerialPacket + 'static>(&self, name: &str, handler: Box<dyn PacketHandler<T> + Send>) -> Box<dyn PacketHandler<T> + Send> {
+        match self.devices.lock() {
+            Ok(mut devices) => devices.push(DeviceState { name: name.to_string(), last_seen: None, online: None }),
+            Err(e) => eprintln!("{:?}", e)
+        }
+
+        Box::new(AvailabilityHandler { availability: self.clone(), name: name.to_string(), handler })
+    }
+
+    fn seen(&self, name: &str) {
+        let changed = match self.devices.lock() {
+            Ok(mut devices) => match devices.iter_mut().find(|d| d.name == name) {
+                Some(device) => {
+                    device.last_seen = Some(Instant::now());
+                    device.online.replace(true) != Some(true)
+                },
+                None => false
+            },
+            Err(e) => {
+                eprintln!("{:?}", e);
+                false
+            }
+        };
+
+        if changed {
+            self.publish(name, true);
+        }
+    }
+
+    /// Reports the devices which have not answered for the timeout as `offline`
+    pub fn expire(&self) {
+        let expired: Vec<String> = match self.devices.lock() {
+            Ok(mut devices) => devices.iter_mut()
+                .filter(|d| d.online != Some(false) && d.last_seen.unwrap_or(self.started).elapsed() >= self.timeout)
+                .map(|d| {
+                    d.online = Some(false);
+                    d.name.clone()
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                Vec::new()
+            }
+        };
+
+        for name in expired {
+            self.publish(&name, false);
+        }
+    }
+
+    fn publish(&self, name: &str, online: bool) {
+        if let Some(mut link_tx) = utils::link_tx_lock(&self.link_tx) {
+            let payload = if online { "online" } else { "offline" };
+
+            if let Err(e) = link_tx.send(Publish::new(Self::topic(name), payload.to_string(), true), None) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+struct AvailabilityHandler<T: SerialPacket> {
+    availability: Availability,
+    name: String,
+    handler: Box<dyn PacketHandler<T> + Send>
+}
+
+impl<T: SerialPacket> PacketHandler<T> for AvailabilityHandler<T> {
+    fn handle(&self, packet: &T, channels: &Channels<T>) -> bool {
+        let handled = self.handler.handle(packet, channels);
+
+        if handled && packet.command() == Command::Response {
+            self.availability.seen(&self.name);
+        }
+
+        handled
+    }
+
+    fn chaining(&self) -> bool { self.handler.chaining() }
+}
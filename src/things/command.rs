@@ -0,0 +1,417 @@
+use std::{sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}, thread::{self, ThreadId}, fmt::Display};
+
+use rumqttd::protocol::{Publish, PublishProperties};
+
+use crate::{mqtt::MqttLink, utils};
+
+use super::payload;
+
+/// Commands without a response in this time are reported as `timeout`.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandOutcome {
+    Ok,
+    Timeout,
+    Rejected,
+    StateMismatch
+}
+
+impl Display for CommandOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandOutcome::Ok => write!(f, "ok"),
+            CommandOutcome::Timeout => write!(f, "timeout"),
+            CommandOutcome::Rejected => write!(f, "rejected"),
+            CommandOutcome::StateMismatch => write!(f, "state_mismatch"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FrameStatus {
+    Queued,
+    Written,
+    Responded
+}
+
+struct PendingCommand {
+    topic: String,
+    payload: String,
+    properties: Option<PublishProperties>,
+    received: Instant,
+
+    /// Frames of this command by port name
+    frames: Vec<(String, Vec<u8>, FrameStatus)>,
+
+    state_topic: String,
+    expected: String,
+    observed: Option<String>
+}
+
+#[derive(Default)]
+struct Commands {
+    active: Option<(ThreadId, PendingCommand)>,
+    pending: Vec<PendingCommand>
+}
+
+/// Follows every `/set` command from MQTT through the serial write and the response to the resulting state,
+/// then publishes the outcome on `{topic}/result` (and the MQTT 5 response topic, if given).
+#[derive(Clone)]
+pub struct CommandTracker {
+    link_tx: Arc<Mutex<Box<dyn MqttLink>>>,
+    commands: Arc<Mutex<Commands>>,
+
+    /// Every command is rejected as `read-only`
+    read_only: bool
+}
+
+impl CommandTracker {
+    pub fn new(link_tx: Arc<Mutex<Box<dyn MqttLink>>>, read_only: bool) -> Self {
+        Self { link_tx, commands: Arc::new(Mutex::new(Commands::default())), read_only }
+    }
+
+    fn lock(&self) -> Option<MutexGuard<'_, Commands>> {
+        match self.commands.lock() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Starts tracking a command. The packets queued on this thread until `end` belong to it.
+    pub fn begin(&self, publish: &Publish, properties: &Option<PublishProperties>) {
+        let topic = String::from_utf8_lossy(&publish.topic).to_string();
+        let payload = String::from_utf8_lossy(&publish.payload).trim().to_string();
+
+        if let Some(mut commands) = self.lock() {
+            commands.active = Some((thread::current().id(), PendingCommand {
+                state_topic: topic.trim_end_matches("/set").to_string(),
+                expected: payload.clone(),
+                topic,
+                payload,
+                properties: properties.clone(),
+                received: Instant::now(),
+                frames: Vec::new(),
+                observed: None
+            }));
+        }
+    }
+
+    /// Overrides the state expected after the command being handled, when it differs from the payload on `{state_topic}/set`.
+    pub fn expect(&self, state_topic: &str, value: &str) {
+        if let Some(mut commands) = self.lock() {
+            if let Some((id, command)) = commands.active.as_mut() {
+                if *id == thread::current().id() {
+                    command.state_topic = state_topic.to_string();
+                    command.expected = value.to_string();
+                }
+            }
+        }
+    }
+
+    pub fn queued(&self, port: &str, frame: Vec<u8>) {
+        if let Some(mut commands) = self.lock() {
+            if let Some((id, command)) = commands.active.as_mut() {
+                if *id == thread::current().id() {
+                    command.frames.push((port.to_string(), frame, FrameStatus::Queued));
+                }
+            }
+        }
+    }
+
+    /// Finishes handling the command, which is rejected if no packet was queued for it.
+    pub fn end(&self) {
+        let rejected = match self.lock() {
+            Some(mut commands) => match commands.active.take() {
+                Some((_, command)) if self.read_only || command.frames.is_empty() => Some(command),
+                Some((_, command)) => {
+                    commands.pending.push(command);
+                    None
+                },
+                None => None
+            },
+            None => None
+        };
+
+        if let Some(command) = rejected {
+            self.publish(&command, CommandOutcome::Rejected, Some(if self.read_only { "read-only" } else { "no packet for the payload" }));
+        }
+    }
+
+    pub fn written(&self, port: &str, frame: &[u8]) {
+        if let Some(mut commands) = self.lock() {
+            let status = commands.pending.iter_mut()
+                .flat_map(|c| c.frames.iter_mut())
+                .find(|(p, f, s)| p == port && f == frame && *s == FrameStatus::Queued);
+
+            if let Some((_, _, status)) = status {
+                *status = FrameStatus::Written;
+            }
+        }
+    }
+
+    /// The device answered `frame`. The command is finished when all of its frames are answered.
+    pub fn responded(&self, port: &str, frame: &[u8]) {
+        let finished = match self.lock() {
+            Some(mut commands) => {
+                let index = commands.pending.iter().position(|c| {
+                    c.frames.iter().any(|(p, f, s)| p == port && f == frame && *s == FrameStatus::Written)
+                });
+
+                match index {
+                    Some(i) => {
+                        let command = &mut commands.pending[i];
+
+                        // Earlier frames on the port, overtaken by this one before their response, are settled with it
+                        let last = command.frames.iter().position(|(p, f, s)| p == port && f == frame && *s == FrameStatus::Written).unwrap_or_default();
+
+                        for (_, _, status) in command.frames[..=last].iter_mut().filter(|(p, _, s)| p == port && *s == FrameStatus::Written) {
+                            *status = FrameStatus::Responded;
+                        }
+
+                        if command.frames.iter().all(|(_, _, s)| *s == FrameStatus::Responded) {
+                            Some(commands.pending.remove(i))
+                        } else {
+                            None
+                        }
+                    },
+                    None => None
+                }
+            },
+            None => None
+        };
+
+        if let Some(command) = finished {
+            let outcome = match &command.observed {
+                Some(state) if !Self::is_same_state(&command.expected, state) => CommandOutcome::StateMismatch,
+                _ => CommandOutcome::Ok
+            };
+
+            self.publish(&command, outcome, None);
+        }
+    }
+
+    /// Records a published state, compared with the expected state when the command is finished.
+    pub fn observe(&self, topic: &str, payload: &str) {
+        if let Some(mut commands) = self.lock() {
+            for command in commands.pending.iter_mut().filter(|c| c.state_topic == topic) {
+                command.observed = Some(payload.to_string());
+            }
+        }
+    }
+
+    /// Reports the commands without a response in `COMMAND_TIMEOUT`.
+    pub fn expire(&self) {
+        self.expire_after(COMMAND_TIMEOUT);
+    }
+
+    fn expire_after(&self, timeout: Duration) {
+        let expired = match self.lock() {
+            Some(mut commands) => {
+                let (expired, pending) = commands.pending.drain(..).partition(|c| c.received.elapsed() >= timeout);
+                commands.pending = pending;
+
+                expired
+            },
+            None => Vec::new()
+        };
+
+        for command in expired {
+            self.publish(&command, CommandOutcome::Timeout, Some("no response from the device"));
+        }
+    }
+
+    fn is_same_state(expected: &str, state: &str) -> bool {
+        expected.eq_ignore_ascii_case(state) || match (payload::parse_number::<f64>(expected.as_bytes()), state.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.trunc() == b.trunc(),
+            _ => matches!(
+                (payload::parse_bool(expected.as_bytes()), payload::parse_bool(state.as_bytes())),
+                (Ok(a), Ok(b)) if a == b
+            )
+        }
+    }
+
+    fn publish(&self, command: &PendingCommand, outcome: CommandOutcome, reason: Option<&str>) {
+        let mut payload = serde_json::json!({
+            "outcome": outcome.to_string(),
+            "payload": command.payload,
+            "state": command.observed,
+            "elapsed": command.received.elapsed().as_millis() as u64
+        });
+
+        if let Some(reason) = reason {
+            payload["reason"] = reason.into();
+        }
+
+        let payload = payload.to_string();
+
+        if let Some(mut link_tx) = utils::link_tx_lock(&self.link_tx) {
+            let mut result = link_tx.publish(format!("{}/result", command.topic), payload.clone());
+
+            if let Some(properties) = &command.properties {
+                if let Some(response_topic) = &properties.response_topic {
+                    let response_properties = PublishProperties {
+                        correlation_data: properties.correlation_data.clone(),
+                        content_type: Some("application/json".to_string()),
+                        ..Default::default()
+                    };
+
+                    result = result.and(link_tx.send_absolute(
+                        Publish::new(response_topic.clone(), payload, false),
+                        Some(response_properties)
+                    ));
+                }
+            }
+
+            if let Err(e) = result {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::recording::RecordingLink;
+
+    use super::*;
+
+    fn outcome(link: &RecordingLink, topic: &str) -> Option<(String, Option<String>)> {
+        let result: serde_json::Value = serde_json::from_str(&link.get(&format!("{}/result", topic))?).unwrap();
+
+        Some((result["outcome"].as_str().unwrap().to_string(), result["reason"].as_str().map(|v| v.to_string())))
+    }
+
+    /// Handles a command on `topic` queuing `frames` on the port `primary`
+    fn command(commands: &CommandTracker, topic: &str, payload: &str, frames: &[&[u8]]) {
+        commands.begin(&Publish::new(topic.to_string(), payload.to_string(), false), &None);
+
+        for frame in frames {
+            commands.queued("primary", frame.to_vec());
+        }
+
+        commands.end();
+    }
+
+    #[test]
+    fn command_is_ok_with_the_expected_state() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "light/0/01/set", "on", &[&[1, 2]]);
+        commands.written("primary", &[1, 2]);
+        commands.observe("light/0/01", "true");
+        commands.responded("primary", &[1, 2]);
+
+        assert_eq!(outcome(&link, "light/0/01/set"), Some(("ok".to_string(), None)));
+    }
+
+    #[test]
+    fn command_is_a_state_mismatch_with_another_state() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "heating/0/temp/set", "25", &[&[1, 2]]);
+        commands.written("primary", &[1, 2]);
+        commands.observe("heating/0/temp", "23");
+        commands.responded("primary", &[1, 2]);
+
+        assert_eq!(outcome(&link, "heating/0/temp/set"), Some(("state_mismatch".to_string(), None)));
+    }
+
+    #[test]
+    fn command_compares_the_overridden_expectation() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        commands.begin(&Publish::new("heating/0/mode/set", "heat", false), &None);
+        commands.expect("heating/0/power", "true");
+        commands.queued("primary", vec![1, 2]);
+        commands.end();
+
+        commands.written("primary", &[1, 2]);
+        commands.observe("heating/0/power", "true");
+        commands.responded("primary", &[1, 2]);
+
+        assert_eq!(outcome(&link, "heating/0/mode/set"), Some(("ok".to_string(), None)));
+    }
+
+    #[test]
+    fn command_without_packet_is_rejected() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "light/0/01/set", "on", &[]);
+
+        assert_eq!(outcome(&link, "light/0/01/set"), Some(("rejected".to_string(), Some("no packet for the payload".to_string()))));
+    }
+
+    #[test]
+    fn command_is_rejected_in_read_only() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), true);
+
+        command(&commands, "light/0/01/set", "on", &[&[1, 2]]);
+
+        assert_eq!(outcome(&link, "light/0/01/set"), Some(("rejected".to_string(), Some("read-only".to_string()))));
+    }
+
+    #[test]
+    fn command_without_response_times_out() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "light/0/01/set", "on", &[&[1, 2]]);
+        commands.written("primary", &[1, 2]);
+
+        commands.expire();
+        assert_eq!(outcome(&link, "light/0/01/set"), None);
+
+        commands.expire_after(Duration::ZERO);
+        assert_eq!(outcome(&link, "light/0/01/set"), Some(("timeout".to_string(), Some("no response from the device".to_string()))));
+
+        commands.responded("primary", &[1, 2]);
+        assert_eq!(link.count("light/0/01/set/result"), 1);
+    }
+
+    #[test]
+    fn response_is_matched_by_the_frame_and_the_port() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "light/0/01/set", "on", &[&[1, 1]]);
+        command(&commands, "light/0/02/set", "on", &[&[2, 2]]);
+
+        commands.responded("primary", &[2, 2]); // Not written yet
+        commands.written("primary", &[1, 1]);
+        commands.written("primary", &[2, 2]);
+        commands.responded("secondary", &[2, 2]);
+        assert!(link.published.lock().unwrap().is_empty());
+
+        commands.responded("primary", &[2, 2]);
+        assert_eq!(outcome(&link, "light/0/01/set"), None);
+        assert_eq!(outcome(&link, "light/0/02/set").map(|(o, _)| o).as_deref(), Some("ok"));
+
+        commands.responded("primary", &[1, 1]);
+        assert_eq!(outcome(&link, "light/0/01/set").map(|(o, _)| o).as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn command_is_finished_when_all_frames_are_answered() {
+        let link = RecordingLink::default();
+        let commands = CommandTracker::new(link.link_tx(), false);
+
+        command(&commands, "heating/0/set", "{}", &[&[1, 1], &[2, 2]]);
+        commands.written("primary", &[1, 1]);
+        commands.written("primary", &[2, 2]);
+
+        commands.responded("primary", &[1, 1]);
+        assert_eq!(outcome(&link, "heating/0/set"), None);
+
+        commands.responded("primary", &[2, 2]);
+        assert_eq!(outcome(&link, "heating/0/set").map(|(o, _)| o).as_deref(), Some("ok"));
+    }
+}
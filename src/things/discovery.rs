@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+
+use rumqttd::protocol::Publish;
+use serde_json::{Value, json};
+
+use crate::{mqtt::MqttLink, utils};
+
+use super::{availability, payload::PayloadStyle};
+
+/// Topic of the birth message of Home Assistant, after which the configs are published again
+pub const STATUS_TOPIC: &str = "homeassistant/status";
+
+/// A Home Assistant MQTT discovery config of an entity
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// `climate`, `fan`, `light`, `sensor`, etc...
+    pub component: &'static str,
+    pub id: String,
+    pub config: Value,
+    /// Availability topic of the device, besides the one of the bridge
+    pub availability: Option<String>
+}
+
+impl DiscoveryConfig {
+    pub fn new(component: &'static str, id: &str, config: Value) -> Self {
+        Self { component, id: id.to_string(), config, availability: None }
+    }
+
+    /// Sets `payload_on` and `payload_off` of the config, if any, to the style of the published states
+    pub fn payload_style(&mut self, style: &PayloadStyle) {
+        for (key, value) in [("payload_on", true), ("payload_off", false)] {
+            if let Some(payload) = self.config.get_mut(key) {
+                *payload = json!(style.bool(value));
+            }
+        }
+    }
+
+    /// Node id of the entity, which also tells the bridges sharing a broker apart by their topic prefix
+    fn node_id(&self, prefix: &str) -> String {
+        format!("uwb_{}{}", namespace(prefix), self.id)
+    }
+
+    pub fn topic(&self, prefix: &str) -> String {
+        format!("homeassistant/{}/{}/config", self.component, self.node_id(prefix))
+    }
+
+    /// Config with the unique id and the prefixed topics, grouped under the bridge device
+    pub fn payload(&self, prefix: &str) -> String {
+        let mut config = self.config.clone();
+
+        if let Some(object) = config.as_object_mut() {
+            for (key, value) in object.iter_mut() {
+                if let (true, Some(topic)) = (key.ends_with("_topic"), value.as_str()) {
+                    *value = json!(format!("{}{}", prefix, topic));
+                }
+            }
+
+            object.insert("unique_id".to_string(), json!(self.node_id(prefix)));
+            object.insert("device".to_string(), device(prefix));
+
+            let topics: Vec<Value> = [Some(availability::BRIDGE_TOPIC.to_string()), self.availability.clone()].into_iter()
+                .flatten()
+                .map(|topic| json!({ "topic": format!("{}{}", prefix, topic) }))
+                .collect();
+
+            object.insert("availability".to_string(), json!(topics));
+            object.insert("availability_mode".to_string(), json!("all"));
+        }
+
+        config.to_string()
+    }
+
+    /// Publishes the configs as retained, for Home Assistant started later
+    pub fn publish_all(configs: &[DiscoveryConfig], link_tx: &Arc<Mutex<Box<dyn MqttLink>>>) {
+        if let Some(mut link_tx) = utils::link_tx_lock(link_tx) {
+            let prefix = link_tx.prefix().to_string();
+
+            for config in configs {
+                if let Err(e) = link_tx.send_absolute(Publish::new(config.topic(&prefix), config.payload(&prefix), true), None) {
+                    eprintln!("{:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Topic prefix in the characters allowed in the ids of Home Assistant (ex. `wallpad/101-1203/` as `wallpad_101_1203_`)
+fn namespace(prefix: &str) -> String {
+    prefix.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+/// The bridge device, which all entities belong to
+fn device(prefix: &str) -> Value {
+    let name = match prefix.trim_end_matches('/') {
+        "" => "Universal Wallpad Bridge".to_string(),
+        home => format!("Universal Wallpad Bridge ({})", home)
+    };
+
+    json!({
+        "identifiers": [format!("uwb_{}bridge", namespace(prefix))],
+        "name": name,
+        "model": env!("CARGO_PKG_NAME"),
+        "sw_version": env!("CARGO_PKG_VERSION")
+    })
+}
@@ -0,0 +1,38 @@
+use rumqttd::protocol::Publish;
+use serde_json::Value;
+
+/// Topic of the JSON document holding all states of a device (ex. `ventilator/state`)
+pub fn state_topic(device: &str) -> String {
+    format!("{}/state", device)
+}
+
+/// Topic of the JSON commands of a device (ex. `ventilator/set`)
+pub fn set_topic(device: &str) -> String {
+    format!("{}/set", device)
+}
+
+/// Splits a JSON command on `{device}/set` into the commands of its fields, in the order of `fields`.
+///
+/// `fields` maps a JSON pointer in the document to the handler of the field (ex. `("/timer/minutes", Self::on_timer)`),
+/// which is given the value of the field as the payload. The fields missing in the document are left as they are.
+pub fn commands<H: Copy>(publish: &Publish, fields: &[(&str, H)]) -> Result<Vec<(H, Publish)>, String> {
+    let document = match serde_json::from_slice::<Value>(&publish.payload) {
+        Ok(v) if v.is_object() => v,
+        Ok(_) => return Err("the payload must be a JSON object".to_string()),
+        Err(e) => return Err(e.to_string())
+    };
+
+    let commands = fields.iter()
+        .filter_map(|(pointer, handler)| {
+            let payload = match document.pointer(pointer)? {
+                Value::String(v) => v.clone(),
+                Value::Null | Value::Object(_) | Value::Array(_) => return None,
+                v => v.to_string()
+            };
+
+            Some((*handler, Publish::new(publish.topic.to_vec(), payload.into_bytes(), false)))
+        })
+        .collect();
+
+    Ok(commands)
+}
@@ -1,35 +1,40 @@
 use std::marker::PhantomData;
 
-use rumqttd::{local::LinkTx, protocol::Publish};
+use rumqttd::protocol::Publish;
 
-use crate::{serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{floor_heating_packet::FloorHeatingDataPacket, Data, DataParseError}}, utils};
+use crate::serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{floor_heating_packet::FloorHeatingDataPacket, Data, DataParseError}};
 
-use super::{Thing, Channels};
+use super::{Thing, Channels, document, payload, router::{Route, Params, Handle}, refresh::Refresh, discovery::DiscoveryConfig, homie::{HomieNode, HomieProperty}};
 
 #[derive(Clone)]
 pub struct FloorHeating<T: SerialPacket> where FloorHeating<T>: Thing<T> {
     pub _marker: PhantomData<T>,
 }
 
-//const FULL_REQUEST_PACKET: &[u8] = &[0xF7, 0x0B, 0x01, 0x18, 0x01, 0x45, 0x10, 0x00, 0x00, 0xB1, 0xEE];
-
 impl FloorHeating<HyundaiPacket> {
-    fn send_data(room_id: u8, data: &[u8], link_tx: &mut LinkTx, output: bool) {
+    /// Asks for the states of all rooms at once, `F7 0B 01 18 01 45 10 00 00 B1 EE`
+    fn full_request() -> HyundaiPacket {
+        HyundaiPacket::new(0x18, Command::Request, 0x45, 0x10, vec![0; 2])
+    }
+
+    fn send_data(room_id: u8, data: &[u8], channels: &Channels<HyundaiPacket>, output: bool) {
         match FloorHeatingDataPacket::parse::<HyundaiPacket>(data) {
             Ok(data) => {
                 if output {
                     println!("{:?}", data);
                 }
                 
-                let result = link_tx
-                    .publish(format!("heating/{}/power", room_id), data.power.unwrap().to_string())
-                    .and(link_tx.publish(format!("heating/{}/temp/current", room_id), data.current_temp.unwrap().to_string()))
-                    .and(link_tx.publish(format!("heating/{}/temp/target", room_id), data.target_temp.unwrap().to_string()));
-                
-        
-                if result.is_err() {
-                    eprintln!("{:?}", result.unwrap_err());
-                }
+                channels.publish(format!("heating/{}/power", room_id), channels.style.bool(data.power.unwrap()));
+                channels.publish(format!("heating/{}/temp/current", room_id), data.current_temp.unwrap().to_string());
+                channels.publish(format!("heating/{}/temp/target", room_id), data.target_temp.unwrap().to_string());
+
+                channels.publish_document(&format!("heating/{}", room_id), serde_json::json!({
+                    "power": data.power.unwrap(),
+                    "temp": {
+                        "current": data.current_temp.unwrap(),
+                        "target": data.target_temp.unwrap()
+                    }
+                }));
             },
             Err(e) => eprintln!("{:?}", e)
         }
@@ -37,11 +42,14 @@ impl FloorHeating<HyundaiPacket> {
 
 
     fn on_response(packet: &HyundaiPacket, channels: &Channels<HyundaiPacket> ) {
-        match utils::link_tx_lock(&channels.link_tx.clone()) {
-            Some(mut link_tx) => {
-                Self::send_data(packet.room_id - 16, &packet.data[1..4], &mut link_tx, true);
-            },
-            _ => ()
+        if packet.data.len() < 4 {
+            eprintln!("{:?}", DataParseError::LengthTooSmall);
+            return;
+        }
+
+        match packet.room_id.checked_sub(0x11) {
+            Some(room_id) => Self::send_data(room_id, &packet.data[1..4], channels, true),
+            None => eprintln!("{:?}", DataParseError::Unsupported)
         }
     }
 
@@ -51,59 +59,96 @@ impl FloorHeating<HyundaiPacket> {
             return;
         }
 
-        match utils::link_tx_lock(&channels.link_tx.clone()) {
-            Some(mut link_tx) => {
-                Self::send_data(0, &packet.data[1..4], &mut link_tx, false);
-                Self::send_data(1, &packet.data[4..7], &mut link_tx, false);
-                Self::send_data(2, &packet.data[7..10], &mut link_tx, false);
-                Self::send_data(3, &packet.data[10..13], &mut link_tx, false);
-            },
-            _ => ()
-        }
+        Self::send_data(0, &packet.data[1..4], channels, false);
+        Self::send_data(1, &packet.data[4..7], channels, false);
+        Self::send_data(2, &packet.data[7..10], channels, false);
+        Self::send_data(3, &packet.data[10..13], channels, false);
     }
 
     
     fn set_temp(room_id: u8, temp: u8, channels: &Channels<HyundaiPacket>) {
+                channels.commands.expect(&format!("heating/{}/temp/target", room_id), &temp.to_string());
+
                 let p = HyundaiPacket::new(
                     0x18, Command::Modify, 0x45, 0x11 + room_id,
                     FloorHeatingDataPacket::create_temp_modify::<HyundaiPacket>(temp.into()).unwrap()
                 );
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
 
     }
 
     fn set_power(room_id: u8, power: bool, channels: &Channels<HyundaiPacket>) {
+                channels.commands.expect(&format!("heating/{}/power", room_id), &channels.style.bool(power));
+
                 let p = HyundaiPacket::new(
                     0x18, Command::Modify, 0x46, 0x11 + room_id,
                     FloorHeatingDataPacket::create_power_modify::<HyundaiPacket>(power).unwrap()
                 );
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
 
     }
 
-    fn set_mode(room_id: u8, mode: &str, channels: &Channels<HyundaiPacket>) {
+    /// Room of the topic, the wallpad has the rooms 0 to 3
+    fn room(params: &Params) -> Result<u8, String> {
+        match params.get::<u8>("room")? {
+            room @ 0..=3 => Ok(room),
+            room => Err(format!("no room {}", room))
+        }
+    }
 
-                let p = HyundaiPacket::new(
-                    0x18, Command::Modify, 0x46, 0x11 + room_id,
-                    FloorHeatingDataPacket::create_power_modify::<HyundaiPacket>(match mode {
-                        "heat" => true,
-                        _ => false
-                    }).unwrap()
-                );
+    fn on_temp(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let temp = payload::parse_number::<f32>(&pk.payload)?;
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+        if !(5.0..=40.0).contains(&temp) {
+            return Err(format!("temperature must be 5 to 40, `{}`", temp));
+        }
+
+        Self::set_temp(Self::room(params)?, temp as u8, ch);
+
+        Ok(())
+    }
+
+    fn on_power(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        Self::set_power(
+            Self::room(params)?,
+            payload::parse_bool(&pk.payload)?,
+            ch
+        );
+
+        Ok(())
+    }
+
+    fn on_mode(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let heat = match payload::text(&pk.payload)?.to_lowercase().as_str() {
+            "heat" => true,
+            "off" => false,
+            mode => return Err(format!("`{}` is not a mode of the heating", mode))
+        };
+
+        Self::set_power(Self::room(params)?, heat, ch);
+
+        Ok(())
+    }
+
+    fn on_document(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let fields: [(&str, Handle<HyundaiPacket>); 4] = [
+            ("/power", Self::on_power),
+            ("/mode", Self::on_mode),
+            ("/temp", Self::on_temp),
+            ("/temp/target", Self::on_temp)
+        ];
 
+        let commands = document::commands(pk, &fields)?;
+
+        ch.staged(|ch| {
+            for (handle, command) in commands {
+                handle(params, &command, ch)?;
+            }
+
+            Ok(())
+        })
     }
 }
 
@@ -134,64 +179,133 @@ impl Thing<HyundaiPacket> for FloorHeating<HyundaiPacket> {
                     _ => ()
                 }
             }),
-            chaining: false,
-            is_primary: true
+            chaining: false
         })
     }
 
-    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>)> {
+    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>)> {
         None
     }
 
-    fn topic_handler(&self) -> fn(&Publish, &Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>) {
-        |pk, ch, _ch2| {
-                    let topic = String::from_utf8_lossy(&pk.topic);
-                    if topic.len() < 9 {
-                        return;
-                    }
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![Self::full_request()]
+    }
 
-                    if topic.starts_with("heating/") {
-                        if !topic.ends_with("/set") {
-                            return;
-                        }
-                        
-                        if topic.ends_with("/temp/set") {                                               // heating/{room_id}/temp/set
-                            match u8::from_str_radix(&topic[8..(topic.len() - 9)], 10) {
-                                Ok(room_id) => Self::set_temp(
-                                    room_id, 
-                                    String::from_utf8_lossy(&pk.payload).parse::<f32>().unwrap_or(5.0) as u8, 
-                                    ch
-                                ),
-                                Err(e) => eprintln!("{:?}", e),
-                            }
-                        } else if topic.ends_with("/power/set") {
-                            match u8::from_str_radix(&topic[8..(topic.len() - 10)], 10) {               // heating/{room_id}/power/set
-                                Ok(room_id) => Self::set_power(
-                                    room_id, 
-                                    String::from_utf8_lossy(&pk.payload).parse::<bool>().unwrap_or(false), 
-                                    ch
-                                ),
-                                Err(e) => eprintln!("{:?}", e),
-                            }
-                        } else if topic.ends_with("/mode/set") {
-                            match u8::from_str_radix(&topic[8..(topic.len() - 9)], 10) {               // heating/{room_id}/mode/set
-                                Ok(room_id) => Self::set_mode(
-                                    room_id, 
-                                    &String::from_utf8_lossy(&pk.payload), 
-                                    ch
-                                ),
-                                Err(e) => eprintln!("{:?}", e),
-                            }
-                        }
-                    }
-        }
+    fn refresh(&self) -> Vec<Refresh<HyundaiPacket>> {
+        vec![Refresh::new("heating", &["heating"], vec![Self::full_request()])]
     }
 
-    fn subscribe(&self, link_tx: &mut LinkTx) {
-        link_tx.subscribe("heating/+/+/set").unwrap();
+    fn discovery(&self) -> Vec<DiscoveryConfig> {
+        (0..4).map(|room_id| DiscoveryConfig::new("climate", &format!("heating_{}", room_id), serde_json::json!({
+            "name": format!("Heating {}", room_id),
+            "modes": ["off", "heat"],
+            "mode_command_topic": format!("heating/{}/mode/set", room_id),
+            "mode_state_topic": format!("heating/{}/power", room_id),
+            "mode_state_template": "{{ 'heat' if value | lower in ['true', 'on'] else 'off' }}",
+            "current_temperature_topic": format!("heating/{}/temp/current", room_id),
+            "temperature_command_topic": format!("heating/{}/temp/set", room_id),
+            "temperature_state_topic": format!("heating/{}/temp/target", room_id),
+            "min_temp": 5,
+            "max_temp": 40,
+            "temp_step": 1,
+            "precision": 1.0
+        }))).collect()
+    }
+
+    fn homie(&self) -> Vec<HomieNode> {
+        (0..4).map(|room_id| HomieNode::new(&format!("heating-{}", room_id), &format!("Heating {}", room_id), "Floor Heating", vec![
+            HomieProperty::new("power", "Power", "boolean", format!("heating/{}/power", room_id))
+                .settable(format!("heating/{}/power/set", room_id)),
+            HomieProperty::new("current-temperature", "Current Temperature", "integer", format!("heating/{}/temp/current", room_id))
+                .unit("°C"),
+            HomieProperty::new("target-temperature", "Target Temperature", "integer", format!("heating/{}/temp/target", room_id))
+                .format("5:40")
+                .unit("°C")
+                .settable(format!("heating/{}/temp/set", room_id))
+        ])).collect()
+    }
+
+    fn routes(&self) -> Vec<Route<HyundaiPacket>> {
+        vec![
+            Route::new("heating/{room:u8}/temp/set", Self::on_temp),
+            Route::new("heating/{room:u8}/power/set", Self::on_power),
+            Route::new("heating/{room:u8}/mode/set", Self::on_mode),
+            Route::new("heating/{room:u8}/set", Self::on_document)
+        ]
     }
 
     fn new() -> Box<dyn Thing<HyundaiPacket> + Send> where Self: Sized {
         Box::new(Self{ _marker: PhantomData})
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::{mqtt::recording::RecordingLink, models::hyundai::FloorHeatingController, serial::{Serial, ISerial, SerialOptions}};
+    use crate::things::{command::CommandTracker, state::StateStore, router::Params};
+
+    use super::*;
+
+    fn serial(link: &RecordingLink) -> Serial<HyundaiPacket> {
+        Serial::<HyundaiPacket>::new(
+            "primary",
+            String::new(),
+            vec![FloorHeating::<HyundaiPacket>::new().handler()],
+            link.link_tx(),
+            CommandTracker::new(link.link_tx(), false),
+            StateStore::new(link.link_tx()),
+            SerialOptions::default()
+        )
+    }
+
+    /// Handles a command on `topic` and answers its packet as the heating controller would
+    fn command(serial: &Serial<HyundaiPacket>, pattern: &str, room: u8, topic: &str, payload: &str) {
+        let route = FloorHeating::<HyundaiPacket>::new().routes().into_iter().find(|r| r.pattern == pattern).unwrap();
+        let publish = Publish::new(topic.to_string(), payload.to_string(), false);
+
+        serial.channels.commands.begin(&publish, &None);
+        route.handler(&serial.channels)(&Params::from(vec![("room", room.to_string())]), &publish).unwrap();
+        serial.channels.commands.end();
+
+        let sent = serial.rx.try_recv().unwrap();
+        serial.channels.commands.written("primary", &sent.to_vec());
+
+        let response = FloorHeatingController::boxed().respond(&sent).unwrap();
+        serial._handle(&response.to_vec(), &mut Some(sent));
+    }
+
+    #[test]
+    fn response_is_published_on_the_room_of_the_command() {
+        for room in 0..4 {
+            let link = RecordingLink::default();
+            let serial = serial(&link);
+
+            command(&serial, "heating/{room:u8}/temp/set", room, &format!("heating/{}/temp/set", room), "25");
+
+            assert_eq!(link.get(&format!("heating/{}/temp/target", room)).as_deref(), Some("25"));
+            assert!(link.retained().iter().all(|(t, _)| t.starts_with(&format!("heating/{}/", room))));
+            assert!(link.get(&format!("heating/{}/temp/set/result", room)).unwrap().contains("\"outcome\":\"ok\""));
+        }
+    }
+
+    #[test]
+    fn power_command_expects_the_power_state() {
+        let link = RecordingLink::default();
+        let serial = serial(&link);
+
+        command(&serial, "heating/{room:u8}/power/set", 2, "heating/2/power/set", "on");
+
+        assert_eq!(link.get("heating/2/power").as_deref(), Some("true"));
+        assert!(link.get("heating/2/power/set/result").unwrap().contains("\"outcome\":\"ok\""));
+    }
+
+    #[test]
+    fn mode_command_expects_the_power_state() {
+        let link = RecordingLink::default();
+        let serial = serial(&link);
+
+        command(&serial, "heating/{room:u8}/mode/set", 1, "heating/1/mode/set", "heat");
+
+        assert_eq!(link.get("heating/1/power").as_deref(), Some("true"));
+        assert!(link.get("heating/1/mode/set/result").unwrap().contains("\"outcome\":\"ok\""));
+    }
+}
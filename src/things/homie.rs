@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+
+use rumqttd::protocol::Publish;
+
+use crate::{mqtt::MqttLink, utils};
+
+use super::payload;
+
+/// Homie convention implemented by the publisher
+pub const HOMIE_VERSION: &str = "4.0.0";
+
+/// `$state` of the device, set to `lost` by the last will of its connection
+pub fn state_topic(device_id: &str) -> String {
+    format!("homie/{}/$state", device_id)
+}
+
+/// A property of a Homie node, mirroring a state topic of a thing
+#[derive(Debug, Clone)]
+pub struct HomieProperty {
+    pub id: &'static str,
+    pub name: String,
+    /// `integer`, `float`, `boolean`, `string` or `enum`
+    pub datatype: &'static str,
+    pub format: Option<String>,
+    pub unit: Option<&'static str>,
+
+    pub state_topic: String,
+    /// Topic of the thing the Homie `/set` commands are routed to, the property is settable if given
+    pub command_topic: Option<String>
+}
+
+impl HomieProperty {
+    pub fn new(id: &'static str, name: &str, datatype: &'static str, state_topic: String) -> Self {
+        Self { id, name: name.to_string(), datatype, format: None, unit: None, state_topic, command_topic: None }
+    }
+
+    pub fn format(mut self, format: &str) -> Self {
+        self.format = Some(format.to_string());
+        self
+    }
+
+    pub fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn settable(mut self, command_topic: String) -> Self {
+        self.command_topic = Some(command_topic);
+        self
+    }
+}
+
+/// A Homie node describing a thing, or a part of it like a room
+#[derive(Debug, Clone)]
+pub struct HomieNode {
+    pub id: String,
+    pub name: String,
+    pub node_type: &'static str,
+    pub properties: Vec<HomieProperty>
+}
+
+impl HomieNode {
+    pub fn new(id: &str, name: &str, node_type: &'static str, properties: Vec<HomieProperty>) -> Self {
+        Self { id: id.to_string(), name: name.to_string(), node_type, properties }
+    }
+}
+
+/// Describes the things as a single Homie device, mirroring their states on `homie/{device}/{node}/{property}`
+/// and routing `homie/{device}/{node}/{property}/set` back to the topics of the things.
+pub struct Homie {
+    pub device_id: String,
+    pub nodes: Vec<HomieNode>
+}
+
+impl Homie {
+    pub fn new(device_id: &str, nodes: Vec<HomieNode>) -> Self {
+        Self { device_id: device_id.to_string(), nodes }
+    }
+
+    fn properties(&self) -> impl Iterator<Item = (&HomieNode, &HomieProperty)> {
+        self.nodes.iter().flat_map(|n| n.properties.iter().map(move |p| (n, p)))
+    }
+
+    fn topic(&self, node: &HomieNode, property: &HomieProperty) -> String {
+        format!("homie/{}/{}/{}", self.device_id, node.id, property.id)
+    }
+
+    pub fn subscribe(&self, link_tx: &mut dyn MqttLink) {
+        for (_, property) in self.properties() {
+            if let Err(e) = link_tx.subscribe(&property.state_topic) {
+                eprintln!("{:?}", e);
+            }
+        }
+
+        if let Err(e) = link_tx.subscribe_absolute(&format!("homie/{}/+/+/set", self.device_id)) {
+            eprintln!("{:?}", e);
+        }
+    }
+
+    /// Publishes the attributes of the device, the nodes and the properties
+    pub fn publish_attributes(&self, link_tx: &Arc<Mutex<Box<dyn MqttLink>>>) {
+        let device = format!("homie/{}", self.device_id);
+
+        let mut attributes = vec![
+            (state_topic(&self.device_id), "init".to_string()),
+            (format!("{}/$homie", device), HOMIE_VERSION.to_string()),
+            (format!("{}/$name", device), "Universal Wallpad Bridge".to_string()),
+            (format!("{}/$extensions", device), String::new()),
+            (format!("{}/$nodes", device), self.nodes.iter().map(|n| n.id.as_str()).collect::<Vec<&str>>().join(","))
+        ];
+
+        for node in &self.nodes {
+            let node_topic = format!("{}/{}", device, node.id);
+
+            attributes.push((format!("{}/$name", node_topic), node.name.clone()));
+            attributes.push((format!("{}/$type", node_topic), node.node_type.to_string()));
+            attributes.push((format!("{}/$properties", node_topic), node.properties.iter().map(|p| p.id).collect::<Vec<&str>>().join(",")));
+
+            for property in &node.properties {
+                let topic = self.topic(node, property);
+
+                attributes.push((format!("{}/$name", topic), property.name.clone()));
+                attributes.push((format!("{}/$datatype", topic), property.datatype.to_string()));
+                attributes.push((format!("{}/$settable", topic), property.command_topic.is_some().to_string()));
+
+                if let Some(format) = &property.format {
+                    attributes.push((format!("{}/$format", topic), format.clone()));
+                }
+
+                if let Some(unit) = property.unit {
+                    attributes.push((format!("{}/$unit", topic), unit.to_string()));
+                }
+            }
+        }
+
+        attributes.push((state_topic(&self.device_id), "ready".to_string()));
+
+        if let Some(mut link_tx) = utils::link_tx_lock(link_tx) {
+            for (topic, payload) in attributes {
+                if let Err(e) = link_tx.send_absolute(Publish::new(topic, payload, true), None) {
+                    eprintln!("{:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Mirrors a state published by a thing on its Homie property, with the booleans always as `true` / `false`
+    pub fn mirror(&self, publish: &Publish, link_tx: &Arc<Mutex<Box<dyn MqttLink>>>) {
+        for (node, property) in self.properties() {
+            if publish.topic != property.state_topic.as_bytes() {
+                continue;
+            }
+
+            let payload = match (property.datatype, payload::parse_bool(&publish.payload)) {
+                ("boolean", Ok(v)) => v.to_string().into_bytes(),
+                _ => publish.payload.to_vec()
+            };
+
+            if let Some(mut link_tx) = utils::link_tx_lock(link_tx) {
+                if let Err(e) = link_tx.send_absolute(Publish::new(self.topic(node, property).into_bytes(), payload, true), None) {
+                    eprintln!("{:?}", e);
+                }
+            }
+        }
+    }
+
+    /// The command of a thing for a Homie `/set` command, if it is on a settable property
+    pub fn command(&self, publish: &Publish) -> Option<Publish> {
+        self.properties()
+            .find(|(node, property)| publish.topic == format!("{}/set", self.topic(node, property)).as_bytes())
+            .and_then(|(_, property)| property.command_topic.as_ref())
+            .map(|topic| Publish::new(topic.clone().into_bytes(), publish.payload.to_vec(), false))
+    }
+}
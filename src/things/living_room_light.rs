@@ -1,8 +1,8 @@
-use rumqttd::{local::LinkTx, protocol::Publish};
+use rumqttd::protocol::Publish;
 
-use crate::{serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{Data, binary_switch_packet::BinarySwitchDataPacket}}, utils};
+use crate::serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{Data, binary_switch_packet::BinarySwitchDataPacket}};
 
-use super::{Thing, Channels};
+use super::{Thing, Channels, document, payload, router::{Route, Params}, refresh::Refresh, discovery::DiscoveryConfig, homie::{HomieNode, HomieProperty}};
 
 #[derive(Clone)]
 pub struct LivingRoomLight<T: SerialPacket> {
@@ -15,17 +15,8 @@ impl LivingRoomLight<HyundaiPacket> {
             Ok(data) => {
                 println!("{:?}", data);
 
-                if data.status.is_some() {
-                    match utils::link_tx_lock(&channels.link_tx.clone()) {
-                        Some(mut link_tx) => {
-                            let result = link_tx.publish(format!("light/0/{:0>2}", id), data.status.unwrap().to_string());    
-    
-                            if result.is_err() {
-                                eprintln!("{:?}", result.unwrap_err());
-                            }
-                        }
-                        _ => ()
-                    }
+                if let Some(status) = data.status {
+                    channels.publish(format!("light/0/{:0>2}", id), channels.style.bool(status));
                 }
         
             },
@@ -33,6 +24,15 @@ impl LivingRoomLight<HyundaiPacket> {
         }
     }
 
+    /// Publishes the document of both lights, which are only given together by the response to the whole room
+    fn on_full_response(buf: &[u8], channels: &Channels<HyundaiPacket>) {
+        let status = |buf: &[u8]| BinarySwitchDataPacket::parse::<HyundaiPacket>(buf).ok().and_then(|data| data.status);
+
+        if let (Some(first), Some(second)) = (status(&buf[1..2]), status(&buf[2..3])) {
+            channels.publish_document("light/0", serde_json::json!({ "01": first, "02": second }));
+        }
+    }
+
     fn set_status(room_id: u8, status: bool, channels: &Channels<HyundaiPacket>) {
 
                 let p = HyundaiPacket::new(
@@ -40,11 +40,37 @@ impl LivingRoomLight<HyundaiPacket> {
                     BinarySwitchDataPacket::create_modify::<HyundaiPacket>(status).unwrap()
                 );
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
+
+    }
+
+    fn on_status(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let value = payload::parse_bool(&pk.payload)?;
+
+        match params.get::<u8>("id")? {
+            0 => {
+                Self::set_status(1, value, ch);
+                Self::set_status(2, value, ch);
+            },
+            id @ (1 | 2) => Self::set_status(id, value, ch),
+            id => return Err(format!("no light {:0>2}", id))
+        }
+
+        Ok(())
+    }
 
+    fn on_document(_: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let fields = [("/00", 0), ("/01", 1), ("/02", 2)];
+
+        let commands = document::commands(pk, &fields)?;
+
+        ch.staged(|ch| {
+            for (id, command) in commands {
+                Self::on_status(&Params::from(vec![("id", id.to_string())]), &command, ch)?;
+            }
+
+            Ok(())
+        })
     }
 }
 
@@ -62,6 +88,7 @@ impl Thing<HyundaiPacket> for LivingRoomLight<HyundaiPacket> {
                             0x10 => {
                                 Self::on_response(1, &pk.data[1..2], ch);
                                 Self::on_response(2, &pk.data[2..3], ch);
+                                Self::on_full_response(&pk.data, ch);
                             },
                             0x11 => Self::on_response(1, &pk.data, ch),
                             0x12 => Self::on_response(2, &pk.data, ch),
@@ -71,49 +98,44 @@ impl Thing<HyundaiPacket> for LivingRoomLight<HyundaiPacket> {
                     _ => ()
                 }
             }),
-            chaining: false,
-            is_primary: true
+            chaining: false
         })
     }
 
-    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>)> {
+    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>)> {
         None
     }
 
-    fn topic_handler(&self) -> fn(&Publish, &Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>) {
-        |pk, ch, _ch2| {
-            let topic = String::from_utf8_lossy(&pk.topic);
-
-            if topic.len() >= 12 {
-                if topic.starts_with("light/") {
-                    if !topic.ends_with("/set") {
-                        return;
-                    }
-
-                    let id = &topic[6..(topic.len() - 4)];
-                    let value = String::from_utf8_lossy(&pk.payload).parse::<bool>().unwrap_or(false);
-
-                    match id {
-                        "0/00" => {
-                            Self::set_status(1, value, &ch);
-                            Self::set_status(2, value, &ch);
-
-                        },
-                        "0/01" => {
-                            Self::set_status(1, value, &ch);
-                        },
-                        "0/02" => {
-                            Self::set_status(2, value, &ch)
-                        },
-                        _ => ()
-                    }
-                }
-            }
-        }
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![HyundaiPacket::new(0x19, Command::Request, 0x40, 0x10, vec![0; 2])]
+    }
+
+    fn refresh(&self) -> Vec<Refresh<HyundaiPacket>> {
+        vec![Refresh::new("light/0", &["light/0"], self.polls())]
+    }
+
+    fn discovery(&self) -> Vec<DiscoveryConfig> {
+        (1..=2).map(|id| DiscoveryConfig::new("light", &format!("light_0_{:0>2}", id), serde_json::json!({
+            "name": format!("Living Room Light {}", id),
+            "command_topic": format!("light/0/{:0>2}/set", id),
+            "state_topic": format!("light/0/{:0>2}", id),
+            "payload_on": "true",
+            "payload_off": "false"
+        }))).collect()
+    }
+
+    fn homie(&self) -> Vec<HomieNode> {
+        vec![HomieNode::new("living-room-lights", "Living Room Lights", "Light", (1..=2).map(|id| {
+            HomieProperty::new(["light-1", "light-2"][id - 1], &format!("Light {}", id), "boolean", format!("light/0/{:0>2}", id))
+                .settable(format!("light/0/{:0>2}/set", id))
+        }).collect())]
     }
 
-    fn subscribe(&self, link_tx: &mut LinkTx) {
-        link_tx.subscribe("light/0/+/set").unwrap();
+    fn routes(&self) -> Vec<Route<HyundaiPacket>> {
+        vec![
+            Route::new("light/0/{id:u8}/set", Self::on_status),
+            Route::new(&document::set_topic("light/0"), Self::on_document)
+        ]
     }
 
     fn new() -> Box<dyn Thing<HyundaiPacket> + Send> {
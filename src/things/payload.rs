@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+/// How the boolean states are published
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum PayloadStyle {
+    /// `true` / `false`
+    #[default]
+    #[clap(name = "true_false")]
+    TrueFalse,
+    /// `ON` / `OFF`
+    #[clap(name = "on_off")]
+    OnOff,
+}
+
+impl PayloadStyle {
+    pub fn bool(&self, value: bool) -> String {
+        match (self, value) {
+            (PayloadStyle::TrueFalse, v) => v.to_string(),
+            (PayloadStyle::OnOff, true) => "ON".to_string(),
+            (PayloadStyle::OnOff, false) => "OFF".to_string(),
+        }
+    }
+}
+
+/// Value of a command payload, given as is (`ON`), as a JSON string (`"ON"`) or in a JSON object (`{"state": "ON"}`, `{"value": 25}`)
+pub fn text(payload: &[u8]) -> Result<String, String> {
+    let payload = String::from_utf8_lossy(payload).trim().to_string();
+
+    if !payload.starts_with(['{', '"']) {
+        return Ok(payload);
+    }
+
+    let value = match serde_json::from_str::<Value>(&payload) {
+        Ok(Value::Object(object)) => ["state", "value"].iter()
+            .find_map(|key| object.get(*key).cloned())
+            .ok_or("no `state` or `value` in the JSON object".to_string())?,
+        Ok(v) => v,
+        Err(e) => return Err(e.to_string())
+    };
+
+    match value {
+        Value::String(v) => Ok(v.trim().to_string()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        v => Err(format!("`{}` is not a value", v))
+    }
+}
+
+/// Accepts `true`/`false`, `ON`/`OFF`, `1`/`0` and `yes`/`no` in any case
+pub fn parse_bool(payload: &[u8]) -> Result<bool, String> {
+    let text = text(payload)?;
+
+    match text.to_lowercase().as_str() {
+        "true" | "on" | "1" | "yes" => Ok(true),
+        "false" | "off" | "0" | "no" => Ok(false),
+        _ => Err(format!("`{}` is not a boolean", text))
+    }
+}
+
+/// Accepts a finite number, also a whole number written with a fraction (ex. `25.0`) for the integer types
+pub fn parse_number<N: FromStr>(payload: &[u8]) -> Result<N, String> {
+    let text = text(payload)?;
+
+    let number = match text.parse::<f64>() {
+        Ok(v) if v.is_finite() => v,
+        _ => return Err(format!("`{}` is not a number", text))
+    };
+
+    if let Ok(v) = text.parse::<N>() {
+        return Ok(v);
+    }
+
+    if number.fract() != 0.0 {
+        return Err(format!("`{}` is not a whole number", text));
+    }
+
+    format!("{}", number as i64).parse::<N>().map_err(|_| format!("`{}` is out of range", text))
+}
+
+/// Accepts a value of an enum by its name
+pub fn parse_enum<E: FromStr>(payload: &[u8]) -> Result<E, String> {
+    let text = text(payload)?;
+
+    text.parse::<E>().map_err(|_| format!("`{}` is not a valid value", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_unwraps_json() {
+        assert_eq!(text(b" ON "), Ok("ON".to_string()));
+        assert_eq!(text(b"\"ON\""), Ok("ON".to_string()));
+        assert_eq!(text(br#"{"state": "ON"}"#), Ok("ON".to_string()));
+        assert_eq!(text(br#"{"value": 25}"#), Ok("25".to_string()));
+        assert_eq!(text(br#"{"state": true}"#), Ok("true".to_string()));
+        assert!(text(br#"{"power": "ON"}"#).is_err());
+        assert!(text(br#"{"state": [1]}"#).is_err());
+        assert!(text(b"{state").is_err());
+    }
+
+    #[test]
+    fn bool_accepts_any_style() {
+        for payload in [&b"true"[..], b"ON", b"on", b"1", b"yes", br#"{"state": "ON"}"#, br#""On""#] {
+            assert_eq!(parse_bool(payload), Ok(true), "{:?}", String::from_utf8_lossy(payload));
+        }
+
+        for payload in [&b"false"[..], b"OFF", b"off", b"0", b"No", br#"{"state": false}"#] {
+            assert_eq!(parse_bool(payload), Ok(false), "{:?}", String::from_utf8_lossy(payload));
+        }
+
+        assert!(parse_bool(b"maybe").is_err());
+        assert!(parse_bool(b"").is_err());
+        assert!(parse_bool(b"2").is_err());
+    }
+
+    #[test]
+    fn number_accepts_whole_fractions() {
+        assert_eq!(parse_number::<u8>(b"25"), Ok(25));
+        assert_eq!(parse_number::<u8>(b"25.0"), Ok(25));
+        assert_eq!(parse_number::<u8>(br#"{"value": 25.0}"#), Ok(25));
+        assert_eq!(parse_number::<f32>(b"25.5"), Ok(25.5));
+        assert!(parse_number::<u8>(b"25.5").is_err());
+        assert!(parse_number::<u8>(b"256").is_err());
+        assert!(parse_number::<u8>(b"-1").is_err());
+        assert!(parse_number::<u8>(b"warm").is_err());
+    }
+
+    #[test]
+    fn number_rejects_non_finite() {
+        assert!(parse_number::<f32>(b"NaN").is_err());
+        assert!(parse_number::<f32>(b"inf").is_err());
+        assert!(parse_number::<u8>(b"NaN").is_err());
+        assert!(parse_number::<f64>(b"1e400").is_err());
+    }
+
+    #[test]
+    fn style_publishes_bool() {
+        assert_eq!(PayloadStyle::TrueFalse.bool(true), "true");
+        assert_eq!(PayloadStyle::OnOff.bool(false), "OFF");
+    }
+}
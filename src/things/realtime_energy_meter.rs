@@ -1,10 +1,8 @@
 use std::marker::PhantomData;
 
-use rumqttd::{local::LinkTx, protocol::Publish};
+use crate::serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{realtime_energy_packet::RealtimeEnergyDataPacket, Data}};
 
-use crate::{serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{realtime_energy_packet::RealtimeEnergyDataPacket, Data}}, utils};
-
-use super::{Thing, Channels};
+use super::{Thing, Channels, router::Route, refresh::Refresh, discovery::DiscoveryConfig, homie::{HomieNode, HomieProperty}};
 
 #[derive(Clone)]
 pub struct RealtimeEnergyMeter<T: SerialPacket> {
@@ -14,34 +12,32 @@ pub struct RealtimeEnergyMeter<T: SerialPacket> {
 //const REQUEST_PACKET: &[u8] = &[0xF7, 0x0B, 0x01, 0x43, 0x01, 0x1F, 0x11, 0x00, 0x00, 0xB1, 0xEE];
 
 impl RealtimeEnergyMeter<HyundaiPacket> {
+    fn request() -> HyundaiPacket {
+        HyundaiPacket::new(0x43, Command::Request, 0x1F, 0x11, RealtimeEnergyDataPacket::create_request::<HyundaiPacket>().unwrap())
+    }
+
     fn on_response(packet: &HyundaiPacket, channels: &Channels<HyundaiPacket>) {
         match RealtimeEnergyDataPacket::parse::<HyundaiPacket>(&packet.data) {
             Ok(data) => {
                 println!("{:?}", data);
                 
-                match utils::link_tx_lock(&channels.link_tx.clone()) {
-                    Some(mut link_tx) => {
-                        let mut result = Ok(0);
-
-                        if data.electric.is_some() {
-                            result = link_tx.publish("electric/meter", data.electric.unwrap().to_string());
-                            
-                        }
-
-                        if data.water.is_some() {
-                            result = result.and(link_tx.publish("water/meter", data.water.unwrap().to_string()));
-                        }
-
-                        if data.gas.is_some() {
-                            result = result.and(link_tx.publish("gas/meter", data.gas.unwrap().to_string()));
-                        }
-
-                        if result.is_err() {
-                            eprintln!("{:?}", result.unwrap_err());
-                        }
-                    }
-                    _ => ()
+                if let Some(v) = data.electric {
+                    channels.publish("electric/meter", v.to_string());
                 }
+
+                if let Some(v) = data.water {
+                    channels.publish("water/meter", v.to_string());
+                }
+
+                if let Some(v) = data.gas {
+                    channels.publish("gas/meter", v.to_string());
+                }
+
+                channels.publish_document("meter", serde_json::json!({
+                    "electric": data.electric,
+                    "water": data.water,
+                    "gas": data.gas
+                }));
             },
             Err(e) => eprintln!("{:?}", e)
         }
@@ -63,23 +59,15 @@ impl Thing<HyundaiPacket> for RealtimeEnergyMeter<HyundaiPacket> {
                     _ => ()
                 }
             }),
-            chaining: false,
-            is_primary: false
+            chaining: false
         })
     }
 
-    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>)> {
-        Some(|_ch_1, ch_2| {
-        if ch_2.is_none() {
-            return;
-        }
-
-        //match ch_2.unwrap().serial_tx.lock() {
+    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>)> {
+        Some(|ch| {
+        //match ch.serial_tx.lock() {
         //    Ok(port) => {
-                let r = ch_2.unwrap().serial_tx.send(HyundaiPacket::new(0x43, Command::Request, 0x1F, 0x11, RealtimeEnergyDataPacket::create_request::<HyundaiPacket>().unwrap()));
-                if r.is_err() {
-                    eprintln!("Realtime Energy Meter {:?}", r.err());
-                }
+                ch.send(Self::request());
             }
         )
 
@@ -87,12 +75,52 @@ impl Thing<HyundaiPacket> for RealtimeEnergyMeter<HyundaiPacket> {
 
     }
 
-    fn topic_handler(&self) -> fn(&Publish, &Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>) {
-        |_, _, _| {}
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        Vec::new() // Fetched by the task, the wallpad does not poll it either
+    }
+
+    fn refresh(&self) -> Vec<Refresh<HyundaiPacket>> {
+        vec![Refresh::new("meter", &["electric/meter", "water/meter", "gas/meter", "meter"], vec![Self::request()])]
+    }
+
+    fn discovery(&self) -> Vec<DiscoveryConfig> {
+        vec![
+            DiscoveryConfig::new("sensor", "electric_meter", serde_json::json!({
+                "name": "Electric Usage",
+                "state_topic": "electric/meter",
+                "device_class": "power",
+                "state_class": "measurement",
+                "unit_of_measurement": "W"
+            })),
+            DiscoveryConfig::new("sensor", "water_meter", serde_json::json!({
+                "name": "Water Usage",
+                "state_topic": "water/meter",
+                "device_class": "water",
+                "state_class": "total_increasing",
+                "unit_of_measurement": "m³",
+                "icon": "mdi:water"
+            })),
+            DiscoveryConfig::new("sensor", "gas_meter", serde_json::json!({
+                "name": "Gas Usage",
+                "state_topic": "gas/meter",
+                "device_class": "gas",
+                "state_class": "total_increasing",
+                "unit_of_measurement": "m³",
+                "icon": "mdi:fire"
+            }))
+        ]
+    }
+
+    fn homie(&self) -> Vec<HomieNode> {
+        vec![HomieNode::new("energy-meter", "Energy Meter", "Realtime Energy Meter", vec![
+            HomieProperty::new("electric", "Electric Usage", "integer", "electric/meter".to_string()).unit("W"),
+            HomieProperty::new("water", "Water Usage", "integer", "water/meter".to_string()).unit("m³"),
+            HomieProperty::new("gas", "Gas Usage", "integer", "gas/meter".to_string()).unit("m³")
+        ])]
     }
 
-    fn subscribe(&self, _link_tx: &mut LinkTx) {
-        
+    fn routes(&self) -> Vec<Route<HyundaiPacket>> {
+        Vec::new()
     }
 
     fn new() -> Box<dyn Thing<HyundaiPacket> + Send> where Self: Sized {
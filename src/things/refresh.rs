@@ -0,0 +1,36 @@
+use crate::serial::packet::SerialPacket;
+
+use super::Channels;
+
+/// Refreshes the devices of all ports at once
+pub const BRIDGE_TOPIC: &str = "bridge/refresh";
+
+/// Asks a device for all of its states on `{device}/refresh` (ex. `heating/refresh`).
+///
+/// The states answered are published again even if unchanged, so a client can tell they are fresh.
+#[derive(Clone)]
+pub struct Refresh<T: SerialPacket> {
+    pub device: String,
+    /// Topics of the states answered by the requests, and the ones below them
+    pub topics: Vec<String>,
+    pub requests: Vec<T>
+}
+
+impl<T: SerialPacket> Refresh<T> {
+    pub fn new(device: &str, topics: &[&str], requests: Vec<T>) -> Self {
+        Self { device: device.to_string(), topics: topics.iter().map(|v| v.to_string()).collect(), requests }
+    }
+
+    pub fn topic(&self) -> String {
+        format!("{}/refresh", self.device)
+    }
+
+    /// Queues the requests on the serial port. In the read-only mode the states are published again on the next poll of the wallpad.
+    pub fn send(&self, channels: &Channels<T>) {
+        channels.states.invalidate(&self.topics);
+
+        for request in &self.requests {
+            channels.send(request.clone());
+        }
+    }
+}
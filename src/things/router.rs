@@ -0,0 +1,412 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::{Arc, Mutex}};
+
+use rumqttd::protocol::Publish;
+
+use crate::{serial::packet::SerialPacket, mqtt::MqttLink, utils};
+
+use super::Channels;
+
+/// Unknown or malformed commands are reported here
+pub const ERROR_TOPIC: &str = "bridge/error";
+
+/// Handles a topic of a thing with the parameters of its pattern. `Err` tells why the command is malformed.
+pub type Handle<T> = fn(&Params, &Publish, &Channels<T>) -> Result<(), String>;
+
+/// A handler bound to the channels of its port
+pub type Handler = Box<dyn Fn(&Params, &Publish) -> Result<(), String> + Send>;
+
+/// A pattern of the topics a thing handles (ex. `heating/{room:u8}/temp/set`)
+pub struct Route<T: SerialPacket> {
+    pub pattern: String,
+    pub handle: Handle<T>
+}
+
+impl<T: SerialPacket + 'static> Route<T> {
+    pub fn new(pattern: &str, handle: Handle<T>) -> Self {
+        Self { pattern: pattern.to_string(), handle }
+    }
+
+    /// Binds the handler to the channels of the port
+    pub fn handler(&self, channels: &Channels<T>) -> Handler {
+        let (handle, channels) = (self.handle, channels.clone());
+
+        Box::new(move |params, publish| handle(params, publish, &channels))
+    }
+}
+
+/// Type of a parameter, `{name}` is a string
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    U8,
+    U16,
+    U32,
+    I32,
+    Str
+}
+
+impl Kind {
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            Kind::U8 => value.parse::<u8>().is_ok(),
+            Kind::U16 => value.parse::<u16>().is_ok(),
+            Kind::U32 => value.parse::<u32>().is_ok(),
+            Kind::I32 => value.parse::<i32>().is_ok(),
+            Kind::Str => !value.is_empty()
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(Kind::U8),
+            "u16" => Ok(Kind::U16),
+            "u32" => Ok(Kind::U32),
+            "i32" => Ok(Kind::I32),
+            "str" => Ok(Kind::Str),
+            _ => Err(format!("unknown type `{}`", s))
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::U8 => write!(f, "u8"),
+            Kind::U16 => write!(f, "u16"),
+            Kind::U32 => write!(f, "u32"),
+            Kind::I32 => write!(f, "i32"),
+            Kind::Str => write!(f, "str"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String, Kind)
+}
+
+/// A parsed pattern, made of the literal levels and the `{name:type}` parameters of a topic
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<Segment>
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s.split('/')
+            .map(|segment| match segment.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+                Some(param) => {
+                    let (name, kind) = match param.split_once(':') {
+                        Some((name, kind)) => (name, kind.parse::<Kind>()?),
+                        None => (param, Kind::Str)
+                    };
+
+                    Ok(Segment::Param(name.to_string(), kind))
+                },
+                None if segment.contains(['+', '#', '{', '}']) => Err(format!("invalid level `{}`", segment)),
+                None => Ok(Segment::Literal(segment.to_string()))
+            })
+            .collect::<Result<Vec<Segment>, String>>()?;
+
+        Ok(Self { segments })
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let levels: Vec<String> = self.segments.iter()
+            .map(|s| match s {
+                Segment::Literal(v) => v.clone(),
+                Segment::Param(name, kind) => format!("{{{}:{}}}", name, kind)
+            })
+            .collect();
+
+        write!(f, "{}", levels.join("/"))
+    }
+}
+
+impl Pattern {
+    /// Topic filter to subscribe to, with `+` in place of the levels between the first and the last one,
+    /// so the unknown commands of the device arrive as well (ex. `heating/+/+/set`)
+    pub fn filter(&self) -> String {
+        let last = self.segments.len() - 1;
+
+        self.segments.iter()
+            .enumerate()
+            .map(|(i, s)| match s {
+                Segment::Literal(v) if i == 0 || i == last => v.as_str(),
+                _ => "+"
+            })
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+}
+
+/// Parameters taken from a topic by its pattern
+#[derive(Debug, Default)]
+pub struct Params {
+    values: Vec<(String, String)>
+}
+
+impl Params {
+    /// A parameter in its type, which is already checked against the pattern
+    pub fn get<V: FromStr>(&self, name: &str) -> Result<V, String> {
+        let value = self.values.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v)
+            .ok_or(format!("no parameter `{}`", name))?;
+
+        value.parse::<V>().map_err(|_| format!("invalid {} `{}`", name, value))
+    }
+}
+
+impl From<Vec<(&str, String)>> for Params {
+    fn from(values: Vec<(&str, String)>) -> Self {
+        Self { values: values.into_iter().map(|(name, value)| (name.to_string(), value)).collect() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteError {
+    /// No route for the topic
+    Unknown,
+    Malformed(String)
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::Unknown => write!(f, "unknown"),
+            RouteError::Malformed(_) => write!(f, "malformed"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    param: Option<(String, Kind, Box<Node>)>,
+    handler: Option<Handler>
+}
+
+impl Node {
+    fn find<'a>(&'a self, levels: &[&str], params: &mut Vec<(String, String)>) -> Result<&'a Handler, RouteError> {
+        let (level, rest) = match levels.split_first() {
+            Some(v) => v,
+            None => return self.handler.as_ref().ok_or(RouteError::Unknown)
+        };
+
+        let mut error = RouteError::Unknown;
+
+        if let Some(node) = self.literals.get(*level) {
+            match node.find(rest, params) {
+                Ok(handler) => return Ok(handler),
+                Err(e) => error = e
+            }
+        }
+
+        if let Some((name, kind, node)) = &self.param {
+            let index = params.len();
+
+            match node.find(rest, params) {
+                Ok(handler) if kind.accepts(level) => {
+                    params.insert(index, (name.clone(), level.to_string()));
+                    return Ok(handler);
+                },
+                Ok(_) => error = RouteError::Malformed(format!("{} must be {}, `{}`", name, kind, level)),
+                Err(RouteError::Malformed(reason)) => error = RouteError::Malformed(reason),
+                Err(RouteError::Unknown) => ()
+            }
+
+            params.truncate(index);
+        }
+
+        Err(error)
+    }
+}
+
+/// Dispatches the topics to the handlers of the things by their patterns.
+///
+/// The levels of a topic are looked up in a tree of the patterns, so a topic costs the same however many routes there are.
+#[derive(Default)]
+pub struct Router {
+    root: Node,
+    filters: Vec<String>
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, pattern: &Pattern, handler: Handler) -> Result<(), String> {
+        let mut node = &mut self.root;
+
+        for segment in &pattern.segments {
+            node = match segment {
+                Segment::Literal(v) => node.literals.entry(v.clone()).or_default(),
+                Segment::Param(name, kind) => {
+                    let (n, k, child) = node.param.get_or_insert_with(|| (name.clone(), *kind, Box::default()));
+
+                    if n != name || k != kind {
+                        return Err(format!("`{{{}:{}}}` conflicts with `{{{}:{}}}`", name, kind, n, k));
+                    }
+
+                    child
+                }
+            };
+        }
+
+        if node.handler.is_some() {
+            return Err(format!("`{}` is already routed", pattern));
+        }
+
+        node.handler = Some(handler);
+
+        if !self.filters.contains(&pattern.filter()) {
+            self.filters.push(pattern.filter());
+        }
+
+        Ok(())
+    }
+
+    /// Topic filters of all routes
+    pub fn filters(&self) -> &[String] {
+        &self.filters
+    }
+
+    pub fn route(&self, publish: &Publish) -> Result<(), RouteError> {
+        let topic = String::from_utf8_lossy(&publish.topic);
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut values = Vec::new();
+
+        let handler = self.root.find(&levels, &mut values)?;
+
+        handler(&Params { values }, publish).map_err(RouteError::Malformed)
+    }
+
+    /// Reports an unknown or malformed command on `bridge/error`
+    pub fn report(link_tx: &Arc<Mutex<Box<dyn MqttLink>>>, publish: &Publish, error: &RouteError) {
+        let mut payload = serde_json::json!({
+            "topic": String::from_utf8_lossy(&publish.topic),
+            "payload": String::from_utf8_lossy(&publish.payload),
+            "error": error.to_string()
+        });
+
+        if let RouteError::Malformed(reason) = error {
+            payload["reason"] = reason.as_str().into();
+        }
+
+        if let Some(mut link_tx) = utils::link_tx_lock(link_tx) {
+            if let Err(e) = link_tx.publish(ERROR_TOPIC.to_string(), payload.to_string()) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Routes the topics to handlers recording their name and parameters
+    fn router(patterns: &[&str]) -> (Router, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut router = Router::new();
+
+        for pattern in patterns {
+            let (calls, name) = (calls.clone(), pattern.to_string());
+
+            router.add(&pattern.parse().unwrap(), Box::new(move |params, _| {
+                calls.lock().unwrap().push(format!("{} {:?}", name, params.values));
+                Ok(())
+            })).unwrap();
+        }
+
+        (router, calls)
+    }
+
+    fn route(router: &Router, topic: &str) -> Result<(), RouteError> {
+        router.route(&Publish::new(topic.to_string(), String::new(), false))
+    }
+
+    #[test]
+    fn pattern_parses_params() {
+        let pattern = "heating/{room:u8}/temp/set".parse::<Pattern>().unwrap();
+
+        assert_eq!(pattern.to_string(), "heating/{room:u8}/temp/set");
+        assert_eq!(pattern.filter(), "heating/+/+/set");
+        assert_eq!("virtual/{name}/{key}".parse::<Pattern>().unwrap().to_string(), "virtual/{name:str}/{key:str}");
+    }
+
+    #[test]
+    fn pattern_rejects_invalid_levels() {
+        assert!("heating/{room:f64}/set".parse::<Pattern>().is_err());
+        assert!("heating/+/set".parse::<Pattern>().is_err());
+        assert!("heating/#".parse::<Pattern>().is_err());
+        assert!("heating/room}/set".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn typed_param_is_rejected() {
+        let (router, calls) = router(&["heating/{room:u8}/temp/set"]);
+
+        assert_eq!(route(&router, "heating/1/temp/set"), Ok(()));
+        assert_eq!(route(&router, "heating/x/temp/set"), Err(RouteError::Malformed("room must be u8, `x`".to_string())));
+        assert_eq!(route(&router, "heating/256/temp/set"), Err(RouteError::Malformed("room must be u8, `256`".to_string())));
+        assert_eq!(route(&router, "heating/1/power/set"), Err(RouteError::Unknown));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn literal_takes_precedence_over_param() {
+        let (router, calls) = router(&["light/{room:u8}/{id:u8}/set", "light/0/set", "light/{room:u8}/set"]);
+
+        route(&router, "light/0/set").unwrap();
+        route(&router, "light/1/set").unwrap();
+        route(&router, "light/0/2/set").unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), vec![
+            "light/0/set []".to_string(),
+            "light/{room:u8}/set [(\"room\", \"1\")]".to_string(),
+            "light/{room:u8}/{id:u8}/set [(\"room\", \"0\"), (\"id\", \"2\")]".to_string()
+        ]);
+    }
+
+    #[test]
+    fn literal_backtracks_to_param() {
+        let (router, calls) = router(&["ventilator/mode/set", "ventilator/{key}/get"]);
+
+        route(&router, "ventilator/mode/get").unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), vec!["ventilator/{key}/get [(\"key\", \"mode\")]".to_string()]);
+    }
+
+    #[test]
+    fn duplicate_routes_are_rejected() {
+        let (mut router, _) = router(&["heating/{room:u8}/temp/set"]);
+
+        assert!(router.add(&"heating/{room:u8}/temp/set".parse().unwrap(), Box::new(|_, _| Ok(()))).is_err());
+        assert!(router.add(&"heating/{id:u8}/power/set".parse().unwrap(), Box::new(|_, _| Ok(()))).is_err());
+        assert!(router.add(&"heating/{room:u16}/power/set".parse().unwrap(), Box::new(|_, _| Ok(()))).is_err());
+        assert!(router.add(&"heating/{room:u8}/power/set".parse().unwrap(), Box::new(|_, _| Ok(()))).is_ok());
+        assert_eq!(router.filters(), ["heating/+/+/set"]);
+    }
+
+    #[test]
+    fn handler_error_is_malformed() {
+        let mut router = Router::new();
+
+        router.add(&"light/0/{id:u8}/set".parse().unwrap(), Box::new(|params, _| Err(format!("no light {}", params.get::<u8>("id")?)))).unwrap();
+
+        assert_eq!(route(&router, "light/0/3/set"), Err(RouteError::Malformed("no light 3".to_string())));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, MutexGuard}};
+
+use rumqttd::protocol::Publish;
+
+use crate::{mqtt::MqttLink, utils};
+
+struct State {
+    value: String,
+    /// Kept for the things only, never published (ex. the mode of the ventilator before it was turned off)
+    internal: bool,
+    /// Published on the next update even if unchanged, as the device was asked for it
+    stale: bool
+}
+
+/// The last state of every device by topic (ex. `heating/1/temp/target`), shared by all things of all ports.
+///
+/// A state is published only when it changes, the unchanged ones are published again by `republish`.
+#[derive(Clone)]
+pub struct StateStore {
+    link_tx: Arc<Mutex<Box<dyn MqttLink>>>,
+    states: Arc<Mutex<HashMap<String, State>>>
+}
+
+impl StateStore {
+    pub fn new(link_tx: Arc<Mutex<Box<dyn MqttLink>>>) -> Self {
+        Self { link_tx, states: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    fn lock(&self) -> Option<MutexGuard<'_, HashMap<String, State>>> {
+        match self.states.lock() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, topic: &str) -> Option<String> {
+        self.lock().and_then(|states| states.get(topic).map(|s| s.value.clone()))
+    }
+
+    /// Keeps a state of a thing without publishing it
+    pub fn remember(&self, key: &str, value: &str) {
+        if let Some(mut states) = self.lock() {
+            states.insert(key.to_string(), State { value: value.to_string(), internal: true, stale: false });
+        }
+    }
+
+    /// Stores a state, publishing it as retained if it changed. Returns `true` if it is published.
+    pub fn publish(&self, topic: &str, value: &str) -> bool {
+        let changed = match self.lock() {
+            Some(mut states) => match states.get(topic) {
+                Some(state) if state.value == value && !state.stale => false,
+                _ => {
+                    states.insert(topic.to_string(), State { value: value.to_string(), internal: false, stale: false });
+                    true
+                }
+            },
+            None => true
+        };
+
+        if changed {
+            self.send(topic, value);
+        }
+
+        changed
+    }
+
+    /// Publishes the states of `topics`, and the ones below them, on their next update even if unchanged
+    pub fn invalidate(&self, topics: &[String]) {
+        if let Some(mut states) = self.lock() {
+            for (topic, state) in states.iter_mut() {
+                if topics.iter().any(|t| topic == t || topic.starts_with(&format!("{}/", t))) {
+                    state.stale = true;
+                }
+            }
+        }
+    }
+
+    /// Publishes all states again, even if unchanged
+    pub fn republish(&self) {
+        let states: Vec<(String, String)> = match self.lock() {
+            Some(states) => states.iter()
+                .filter(|(_, s)| !s.internal)
+                .map(|(topic, s)| (topic.clone(), s.value.clone()))
+                .collect(),
+            None => Vec::new()
+        };
+
+        for (topic, value) in states {
+            self.send(&topic, &value);
+        }
+    }
+
+    fn send(&self, topic: &str, value: &str) {
+        if let Some(mut link_tx) = utils::link_tx_lock(&self.link_tx) {
+            if let Err(e) = link_tx.send(Publish::new(topic.to_string(), value.to_string(), true), None) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mqtt::recording::RecordingLink;
+
+    use super::*;
+
+    #[test]
+    fn publishes_only_changes() {
+        let link = RecordingLink::default();
+        let states = StateStore::new(link.link_tx());
+
+        assert!(states.publish("heating/0/power", "true"));
+        assert!(!states.publish("heating/0/power", "true"));
+        assert!(states.publish("heating/0/power", "false"));
+
+        assert_eq!(link.retained(), vec![
+            ("heating/0/power".to_string(), "true".to_string()),
+            ("heating/0/power".to_string(), "false".to_string())
+        ]);
+        assert_eq!(states.get("heating/0/power").as_deref(), Some("false"));
+    }
+
+    #[test]
+    fn republishes_all_states_but_the_internal_ones() {
+        let link = RecordingLink::default();
+        let states = StateStore::new(link.link_tx());
+
+        states.publish("heating/0/power", "true");
+        states.publish("ventilator/mode", "Normal");
+        states.remember("ventilator/mode/last", "Normal");
+        link.clear();
+
+        states.republish(); // As on every connection to the broker
+
+        let mut republished = link.retained();
+        republished.sort();
+
+        assert_eq!(republished, vec![
+            ("heating/0/power".to_string(), "true".to_string()),
+            ("ventilator/mode".to_string(), "Normal".to_string())
+        ]);
+        assert!(!states.publish("heating/0/power", "true"));
+    }
+
+    #[test]
+    fn invalidated_states_are_published_once_unchanged() {
+        let link = RecordingLink::default();
+        let states = StateStore::new(link.link_tx());
+
+        states.publish("heating/0/power", "true");
+        states.publish("heating/0/temp/target", "23");
+        states.publish("heating/1/power", "true");
+        states.publish("heating/10/power", "true");
+
+        states.invalidate(&["heating/0".to_string()]);
+
+        assert!(states.publish("heating/0/power", "true"));
+        assert!(states.publish("heating/0/temp/target", "23"));
+        assert!(!states.publish("heating/0/power", "true"));
+        assert!(!states.publish("heating/1/power", "true"));
+        assert!(!states.publish("heating/10/power", "true"));
+    }
+
+    #[test]
+    fn remembered_states_are_never_published() {
+        let link = RecordingLink::default();
+        let states = StateStore::new(link.link_tx());
+
+        states.remember("ventilator/mode/last", "Passthrough");
+
+        assert_eq!(states.get("ventilator/mode/last").as_deref(), Some("Passthrough"));
+        assert!(link.published.lock().unwrap().is_empty());
+    }
+}
@@ -1,95 +1,82 @@
 use std::{marker::PhantomData, time::Duration};
 
 
-use rumqttd::{local::LinkTx, protocol::Publish};
+use rumqttd::protocol::Publish;
 
 
-use crate::{serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{Data, ventilator_packet::{VentilatorDataPacket, VentilatorMode, VentilatorFanSpeed}}}, utils::{self, DurationUtils}};
+use crate::{serial::{packet::{hyundai::{HyundaiPacket, HyundaiPacketHandler}, SerialPacket, Command, PacketHandler}, data::{Data, ventilator_packet::{VentilatorDataPacket, VentilatorMode, VentilatorFanSpeed}}}, utils::DurationUtils};
 
-use super::{Thing, Channels};
+use super::{Thing, Channels, document, payload, router::{Route, Params, Handle}, refresh::Refresh, discovery::DiscoveryConfig, homie::{HomieNode, HomieProperty}};
 
 #[derive(Clone)]
 pub struct Ventilator<T: SerialPacket> {
     _marker: PhantomData<T>,
 }
 
-static mut LATEST_MODE: VentilatorMode = VentilatorMode::Normal;
-static mut LATEST_STATE: bool = false;
+/// Key of the mode in the state store, kept while the ventilator is off
+const LAST_MODE_KEY: &str = "ventilator/mode/last";
+
+/// Longest timer, as the wallpad sends its hours in a byte
+const MAX_TIMER_MINUTES: u64 = 255 * 60 + 59;
 
 impl Ventilator<HyundaiPacket> {
+    /// Power in the last response
+    fn power(channels: &Channels<HyundaiPacket>) -> bool {
+        channels.states.get("ventilator/power").and_then(|v| payload::parse_bool(v.as_bytes()).ok()).unwrap_or_default()
+    }
+
+    /// Mode the last time it was on
+    fn last_mode(channels: &Channels<HyundaiPacket>) -> VentilatorMode {
+        channels.states.get(LAST_MODE_KEY).and_then(|v| v.parse::<VentilatorMode>().ok()).unwrap_or(VentilatorMode::Normal)
+    }
+
     fn on_response(packet: &HyundaiPacket, channels: &Channels<HyundaiPacket>) {
         match VentilatorDataPacket::parse::<HyundaiPacket>(&packet.data) {
             Ok(data) => {
                 println!("{:?}", data);
 
-                match utils::link_tx_lock(&channels.link_tx.clone()) {
-                    Some(mut link_tx) => {
-                        
-                            let mut result = unsafe {
-                                LATEST_STATE = data.power.unwrap_or_default();
-                            
-                                link_tx.publish(format!("ventilator/power"), LATEST_STATE.to_string())
-                            }
-                            .and(link_tx.publish(format!("ventilator/mode"), data.mode.as_ref().unwrap_or(&VentilatorMode::Off).to_string()))
-                            .and(link_tx.publish(format!("ventilator/fan_speed"), data.fan_speed.as_ref().unwrap_or(&VentilatorFanSpeed::Low).to_level().to_string()));
-                            
-                            unsafe {
-                                if LATEST_STATE {
-                                    LATEST_MODE = data.mode.unwrap();
-                                }
-                            }
-                            
-                            /*
-                            let mode = data.mode.unwrap();
-
-                            if mode != VentilatorMode::Normal {
-                                match utils::serial_tx_lock(&channels.serial_tx.clone()) {
-                                    Some(link_tx) => {
-                                        let mut pkt = packet.clone();
-
-                                        pkt.data[1] = VentilatorMode::Normal.as_u16::<HyundaiPacket>() as u8 + (pkt.data[1] - mode.as_u16::<HyundaiPacket>() as u8);
-
-                                        match link_tx.send((pkt, 2)) {
-                                            Ok(_) => (),
-                                            Err(e) => eprintln!("{:?}", e)
-                                        }
-                                    }
-                                    _ => ()
-                                }
-                            }
-                            */
-
-                            
-                            result = result.and(link_tx.publish(format!("ventilator/timer/status"), data.setting_time.is_some().to_string()));
-
-                            if data.setting_time.is_some() {
-                                result = result.and(link_tx.publish(format!("ventilator/timer"), data.setting_time.as_ref().unwrap_or(&Duration::ZERO).as_minutes().to_string()))    
-                            }
-
-                            result = result.and(link_tx.publish(format!("ventilator/timer/remaining"), if data.remaining_time.is_some() {
-                                data.remaining_time.as_ref().unwrap_or(&Duration::ZERO).as_minutes().to_string()
-                            } else {
-                                "0".into()
-                            }));
-                            
-                                
-                            if result.is_err() {
-                                eprintln!("{:?}", result.unwrap_err());
-                            }
-                    },
-                    _ => ()
+                channels.publish("ventilator/power", channels.style.bool(data.power.unwrap_or_default()));
+
+                channels.publish("ventilator/mode", data.mode.as_ref().unwrap_or(&VentilatorMode::Off).to_string());
+                channels.publish("ventilator/fan_speed", data.fan_speed.as_ref().unwrap_or(&VentilatorFanSpeed::Low).to_level().to_string());
+
+                if let (true, Some(mode)) = (data.power.unwrap_or_default(), &data.mode) {
+                    channels.states.remember(LAST_MODE_KEY, &mode.to_string());
                 }
+
+                channels.publish("ventilator/timer/status", channels.style.bool(data.setting_time.is_some()));
+
+                if data.setting_time.is_some() {
+                    channels.publish("ventilator/timer", data.setting_time.as_ref().unwrap_or(&Duration::ZERO).as_minutes().to_string());
+                }
+
+                channels.publish("ventilator/timer/remaining", if data.remaining_time.is_some() {
+                    data.remaining_time.as_ref().unwrap_or(&Duration::ZERO).as_minutes().to_string()
+                } else {
+                    "0".into()
+                });
+
+                channels.publish_document("ventilator", serde_json::json!({
+                    "power": data.power.unwrap_or_default(),
+                    "mode": data.mode.as_ref().unwrap_or(&VentilatorMode::Off).to_string(),
+                    "fan_speed": data.fan_speed.as_ref().unwrap_or(&VentilatorFanSpeed::Low).to_level(),
+                    "timer": {
+                        "status": data.setting_time.is_some(),
+                        "minutes": data.setting_time.as_ref().map(|v| v.as_minutes()),
+                        "remaining": data.remaining_time.as_ref().unwrap_or(&Duration::ZERO).as_minutes()
+                    }
+                }));
             },
             Err(e) => eprintln!("{:?}", e)
         }
     }
 
     fn set_power(value: bool, channels: &Channels<HyundaiPacket>) {
-        Self::set_mode(if value { unsafe { match &LATEST_MODE {
+        Self::set_mode(if value { match Self::last_mode(channels) {
             VentilatorMode::Off => &VentilatorMode::Normal,
             VentilatorMode::Normal => &VentilatorMode::Passthrough,
             VentilatorMode::Passthrough => &VentilatorMode::Normal,
-        } } } else {
+        } } else {
             &VentilatorMode::Off
         }, channels)
     }
@@ -100,38 +87,25 @@ impl Ventilator<HyundaiPacket> {
                     VentilatorDataPacket::create_mode_modify::<HyundaiPacket>(&VentilatorMode::Normal).unwrap()
                 );
                 
-                unsafe {
-                    if !LATEST_STATE && (*value != VentilatorMode::Normal || *value != VentilatorMode::Off) {
-                        match channels.serial_tx.send(p.clone()) {
-                            Ok(_) => (),
-                            Err(e) => eprintln!("{:?}", e)
-                        }
-                    }
+                if !Self::power(channels) && (*value != VentilatorMode::Normal || *value != VentilatorMode::Off) {
+                    channels.send(p.clone());
                 }
 
                 p.data = VentilatorDataPacket::create_mode_modify::<HyundaiPacket>(&value).unwrap();
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
     }
 
     fn set_timer(value: &Duration, channels: &Channels<HyundaiPacket>) {
-        unsafe {
-            if !LATEST_STATE {
-                Self::set_power(true, channels);
-            } 
+        if !Self::power(channels) {
+            Self::set_power(true, channels);
         }
                 let p = HyundaiPacket::new(
                     0x2B, Command::Modify, 0x40, 0x11,
                     VentilatorDataPacket::create_timer_modify::<HyundaiPacket>(value).unwrap()
                 );
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
     }
 
     fn set_fan_speed(value: &VentilatorFanSpeed, channels: &Channels<HyundaiPacket>) {
@@ -141,10 +115,64 @@ impl Ventilator<HyundaiPacket> {
                     VentilatorDataPacket::create_fan_modify::<HyundaiPacket>(value).unwrap()
                 );
 
-                match channels.serial_tx.send(p) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{:?}", e)
-                }
+                channels.send(p);
+    }
+
+    fn on_power(_: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        Self::set_power(payload::parse_bool(&pk.payload)?, ch);
+
+        Ok(())
+    }
+
+    fn on_mode(_: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        Self::set_mode(&payload::parse_enum::<VentilatorMode>(&pk.payload)?, ch);
+
+        Ok(())
+    }
+
+    fn on_fan_speed(_: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let speed = payload::text(&pk.payload)?;
+
+        if speed == "0" {
+            ch.commands.expect("ventilator/power", &ch.style.bool(false));
+            Self::set_power(false, ch);
+        } else {
+            Self::set_fan_speed(&payload::parse_enum::<VentilatorFanSpeed>(speed.as_bytes())?, ch);
+        }
+
+        Ok(())
+    }
+
+    fn on_timer(_: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let minutes = payload::parse_number::<u64>(&pk.payload)?;
+
+        if minutes > MAX_TIMER_MINUTES {
+            return Err(format!("timer must be 0 to {} minutes, `{}`", MAX_TIMER_MINUTES, minutes));
+        }
+
+        Self::set_timer(&Duration::from_minutes(minutes), ch);
+
+        Ok(())
+    }
+
+    fn on_document(params: &Params, pk: &Publish, ch: &Channels<HyundaiPacket>) -> Result<(), String> {
+        let fields: [(&str, Handle<HyundaiPacket>); 5] = [
+            ("/power", Self::on_power),
+            ("/mode", Self::on_mode),
+            ("/fan_speed", Self::on_fan_speed),
+            ("/timer", Self::on_timer),
+            ("/timer/minutes", Self::on_timer)
+        ];
+
+        let commands = document::commands(pk, &fields)?;
+
+        ch.staged(|ch| {
+            for (handle, command) in commands {
+                handle(params, &command, ch)?;
+            }
+
+            Ok(())
+        })
     }
 
     pub fn new() -> Box<dyn Thing<HyundaiPacket> + Send> {
@@ -165,66 +193,55 @@ impl Thing<HyundaiPacket> for Ventilator<HyundaiPacket> {
                     _ => ()
                 }
             }),
-            chaining: true,
-            is_primary: true
+            chaining: true
         })
     }
 
-    fn topic_handler(&self) -> fn(&Publish, &Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>) {
-        |pk, ch, _ch2| {
-            let topic = String::from_utf8_lossy(&pk.topic);
-
-            if topic.len() >= 12 {
-                if topic.starts_with("ventilator/") {
-                    if !topic.ends_with("/set") {
-                        return;
-                    }
+    fn discovery(&self) -> Vec<DiscoveryConfig> {
+        vec![DiscoveryConfig::new("fan", "ventilator", serde_json::json!({
+            "name": "Ventilator",
+            "command_topic": "ventilator/power/set",
+            "state_topic": "ventilator/power",
+            "payload_on": "true",
+            "payload_off": "false",
+            "percentage_command_topic": "ventilator/fan_speed/set",
+            "percentage_state_topic": "ventilator/fan_speed",
+            "speed_range_min": 1,
+            "speed_range_max": 3,
+            "preset_modes": [VentilatorMode::Normal.to_string(), VentilatorMode::Passthrough.to_string()],
+            "preset_mode_command_topic": "ventilator/mode/set",
+            "preset_mode_state_topic": "ventilator/mode",
+            // `Off` is reported as the mode while the power is off, which is not a preset
+            "preset_mode_value_template": "{{ value if value != 'Off' else 'None' }}"
+        }))]
+    }
 
-                    let method = &topic[11..(topic.len() - 4)];
-                    let payload = String::from_utf8_lossy(&pk.payload);
-                    println!("{}",payload);
-                    match method {
-                        "power" => {
-                            Self::set_power(payload.parse::<bool>().unwrap_or_default(), ch);
-                        },
-                        "mode" => {
-                            Self::set_mode(&payload.parse::<VentilatorMode>().unwrap_or_default(), ch);
-                        },
-                        "fan_speed" => {
-                            if payload == "0" {
-                                Self::set_power(false, ch);
-                            } else {
-                                Self::set_fan_speed(&payload.parse::<VentilatorFanSpeed>().unwrap_or_default(), ch);
-                            }
-                        },
-                        "timer" => {
-                            Self::set_timer(&Duration::from_minutes(payload.parse::<u64>().unwrap_or_default()), ch);
-                        },
-                        _ => {
-                           /*match utils::serial_tx_lock(&ch.serial_tx.clone()) {
-                                Some(link_tx) => {
-                                    let mut p = HyundaiPacket::new(
-                                        0x2B, Command::Modify, 0x40, 0x11,
-                                        vec![1, payload.parse::<u8>().unwrap()]
-                                    );
-                    
-                                    match link_tx.send(p) {
-                                        Ok(_) => (),
-                                        Err(e) => eprintln!("{:?}", e)
-                                    }
-                                }
-                                _ => ()
-                            }
-                            */
-                        }
-                    }
-                }
-            }
-        }
+    fn homie(&self) -> Vec<HomieNode> {
+        vec![HomieNode::new("ventilator", "Ventilator", "Ventilator", vec![
+            HomieProperty::new("power", "Power", "boolean", "ventilator/power".to_string())
+                .settable("ventilator/power/set".to_string()),
+            HomieProperty::new("mode", "Mode", "enum", "ventilator/mode".to_string())
+                .format(&[VentilatorMode::Off, VentilatorMode::Normal, VentilatorMode::Passthrough].map(|v| v.to_string()).join(","))
+                .settable("ventilator/mode/set".to_string()),
+            HomieProperty::new("fan-speed", "Fan Speed", "integer", "ventilator/fan_speed".to_string())
+                .format("0:3") // 0 turns it off
+                .settable("ventilator/fan_speed/set".to_string()),
+            HomieProperty::new("timer", "Timer", "integer", "ventilator/timer".to_string())
+                .unit("min")
+                .settable("ventilator/timer/set".to_string()),
+            HomieProperty::new("remaining-time", "Remaining Time", "integer", "ventilator/timer/remaining".to_string())
+                .unit("min")
+        ])]
     }
 
-    fn subscribe(&self, link_tx: &mut LinkTx) {
-        link_tx.subscribe("ventilator/+/set").unwrap();
+    fn routes(&self) -> Vec<Route<HyundaiPacket>> {
+        vec![
+            Route::new("ventilator/power/set", Self::on_power),
+            Route::new("ventilator/mode/set", Self::on_mode),
+            Route::new("ventilator/fan_speed/set", Self::on_fan_speed),
+            Route::new("ventilator/timer/set", Self::on_timer),
+            Route::new(&document::set_topic("ventilator"), Self::on_document)
+        ]
     }
 
     fn new() -> Box<dyn Thing<HyundaiPacket> + Send> where Self: Sized {
@@ -233,7 +250,60 @@ impl Thing<HyundaiPacket> for Ventilator<HyundaiPacket> {
         })
     }
 
-    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>, &Option<&Channels<HyundaiPacket>>)> {
+    fn task(&self) -> Option<fn(&Channels<HyundaiPacket>)> {
         None
     }
+
+    fn polls(&self) -> Vec<HyundaiPacket> {
+        vec![HyundaiPacket::new(0x2B, Command::Request, 0x40, 0x11, vec![0; 2])]
+    }
+
+    fn refresh(&self) -> Vec<Refresh<HyundaiPacket>> {
+        vec![Refresh::new("ventilator", &["ventilator"], self.polls())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mqtt::recording::RecordingLink, serial::{Serial, ISerial, SerialOptions}};
+    use crate::things::{command::CommandTracker, state::StateStore};
+
+    use super::*;
+
+    fn serial(link: &RecordingLink) -> Serial<HyundaiPacket> {
+        Serial::<HyundaiPacket>::new(
+            "primary",
+            String::new(),
+            Vec::new(),
+            link.link_tx(),
+            CommandTracker::new(link.link_tx(), false),
+            StateStore::new(link.link_tx()),
+            SerialOptions::default()
+        )
+    }
+
+    fn timer(serial: &Serial<HyundaiPacket>, minutes: &str) -> Result<(), String> {
+        Ventilator::on_timer(&Params::from(Vec::new()), &Publish::new("ventilator/timer/set".to_string(), minutes.to_string(), false), &serial.channels)
+    }
+
+    #[test]
+    fn timer_is_sent_in_hours_and_minutes() {
+        let link = RecordingLink::default();
+        let serial = serial(&link);
+        serial.channels.states.publish("ventilator/power", "true");
+
+        timer(&serial, "15359").unwrap();
+
+        assert_eq!(serial.rx.try_recv().unwrap().data, vec![0x15, 255, 59]);
+    }
+
+    #[test]
+    fn timer_beyond_the_hours_of_a_byte_is_rejected() {
+        let link = RecordingLink::default();
+        let serial = serial(&link);
+
+        assert!(timer(&serial, "15360").is_err());
+        assert!(timer(&serial, &u64::MAX.to_string()).is_err());
+        assert!(serial.rx.try_recv().is_err());
+    }
 }
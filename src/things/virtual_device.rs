@@ -0,0 +1,162 @@
+use std::sync::{Arc, Mutex};
+
+
+use crate::{serial::packet::{SerialPacket, PacketHandler, Command}, models::DeviceModel};
+
+use super::Channels;
+
+/// Answers the polls of the wallpad in place of a missing device, whose real state lives in MQTT.
+///
+/// The state is taken from `virtual/{name}/{key}`, and the commands of the wallpad are published on `virtual/{name}/{key}/set`.
+#[derive(Clone)]
+pub struct VirtualDevice<T: SerialPacket> {
+    pub name: String,
+    device: Arc<Mutex<Box<dyn DeviceModel<T> + Send>>>
+}
+
+impl<T: SerialPacket + 'static> VirtualDevice<T> {
+    pub fn new(name: &str, device: Box<dyn DeviceModel<T> + Send>) -> Self {
+        Self { name: name.to_string(), device: Arc::new(Mutex::new(device)) }
+    }
+
+    /// Pattern of the states of the device
+    pub fn pattern(&self) -> String {
+        format!("virtual/{}/{{key}}", self.name)
+    }
+
+    pub fn handler(&self) -> Box<dyn PacketHandler<T> + Send> {
+        Box::new(VirtualDeviceHandler { device: self.clone() })
+    }
+
+    /// Applies a state published on `virtual/{name}/{key}`, `Err` tells why it is malformed
+    pub fn update(&self, key: &str, payload: &[u8]) -> Result<(), String> {
+        match self.device.lock() {
+            Ok(mut device) => device.update(key, String::from_utf8_lossy(payload).trim()),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct VirtualDeviceHandler<T: SerialPacket> {
+    device: VirtualDevice<T>
+}
+
+impl<T: SerialPacket + 'static> PacketHandler<T> for VirtualDeviceHandler<T> {
+    fn handle(&self, packet: &T, channels: &Channels<T>) -> bool {
+        if packet.command() == Command::Response {
+            return false;
+        }
+
+        let (response, changes) = match self.device.device.lock() {
+            Ok(mut device) => {
+                if device.device_id() != packet.device_id() {
+                    return false;
+                }
+
+                let before = device.state();
+                let response = device.respond(packet);
+
+                let changes: Vec<(String, String)> = device.state().into_iter()
+                    .filter(|v| !before.contains(v))
+                    .collect();
+
+                (response, changes)
+            },
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return false;
+            }
+        };
+
+        if let Some(response) = response {
+            channels.reply(response);
+        }
+
+        for (key, value) in changes { // Changed by the wallpad, to be applied on the real device
+            channels.publish_command(format!("virtual/{}/{}/set", self.device.name, key), value);
+        }
+
+        true
+    }
+
+    fn chaining(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mqtt::recording::RecordingLink, models::hyundai::{LivingRoomLights, CentralVentilator}, utils};
+    use crate::serial::{Serial, ISerial, SerialOptions, packet::hyundai::HyundaiPacket};
+    use crate::things::{command::CommandTracker, state::StateStore};
+
+    use super::*;
+
+    fn serial(link: &RecordingLink, device: &VirtualDevice<HyundaiPacket>) -> Serial<HyundaiPacket> {
+        Serial::<HyundaiPacket>::new(
+            "primary",
+            String::new(),
+            vec![device.handler()],
+            link.link_tx(),
+            CommandTracker::new(link.link_tx(), false),
+            StateStore::new(link.link_tx()),
+            SerialOptions::default()
+        )
+    }
+
+    #[test]
+    fn answers_the_poll_with_the_state() {
+        let link = RecordingLink::default();
+        let device = VirtualDevice::new("living_room_lights", LivingRoomLights::boxed());
+        let serial = serial(&link, &device);
+
+        // Polls of the wallpad, and the responses of the real lights in the capture of the simulator
+        serial._handle(&utils::from_hex("F7 0B 01 19 01 40 10 00 00 B5 EE").unwrap(), &mut None);
+        assert_eq!(utils::to_hex(&serial.replies.try_recv().unwrap().to_vec()), "F7 0C 01 19 04 40 10 00 02 02 B7 EE");
+
+        device.update("01", b"true").unwrap();
+
+        serial._handle(&utils::from_hex("F7 0B 01 19 01 40 10 00 00 B5 EE").unwrap(), &mut None);
+        let response = serial.replies.try_recv().unwrap();
+
+        assert_eq!((response.device_id, response.command, response.device_sub_id, response.room_id), (0x19, Command::Response, 0x40, 0x10));
+        assert_eq!(response.data, vec![0x00, 0x01, 0x02]);
+        assert!(serial.rx.try_recv().is_err()); // Written right after the poll, never queued
+    }
+
+    #[test]
+    fn publishes_the_commands_of_the_wallpad() {
+        let link = RecordingLink::default();
+        let device = VirtualDevice::new("ventilator", CentralVentilator::boxed());
+        let serial = serial(&link, &device);
+
+        serial._handle(&HyundaiPacket::new(0x2B, Command::Modify, 0x40, 0x11, vec![0x01, 0x00]).to_vec(), &mut None);
+
+        let response = serial.replies.try_recv().unwrap();
+
+        assert_eq!((response.device_id, response.command, response.room_id), (0x2B, Command::Response, 0x11));
+        assert_eq!(link.get("virtual/ventilator/power/set").as_deref(), Some("true"));
+        assert_eq!(link.get("virtual/ventilator/mode/set").as_deref(), Some("Normal"));
+    }
+
+    #[test]
+    fn ignores_other_devices_and_responses() {
+        let link = RecordingLink::default();
+        let device = VirtualDevice::new("living_room_lights", LivingRoomLights::boxed());
+        let serial = serial(&link, &device);
+
+        serial._handle(&utils::from_hex("F7 0B 01 2B 01 40 11 00 00 86 EE").unwrap(), &mut None);
+        serial._handle(&utils::from_hex("F7 0C 01 19 04 40 10 00 02 02 B7 EE").unwrap(), &mut None);
+
+        assert!(serial.replies.try_recv().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_states() {
+        let device = VirtualDevice::<HyundaiPacket>::new("living_room_lights", LivingRoomLights::boxed());
+
+        assert!(device.update("03", b"true").is_err());
+        assert!(device.update("01", b"maybe").is_err());
+    }
+}
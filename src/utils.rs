@@ -1,6 +1,6 @@
 use std::{sync::{Mutex, Arc, MutexGuard}, time::Duration};
 
-use rumqttd::local::LinkTx;
+use crate::mqtt::MqttLink;
 
 pub fn xor_checksum(buf: &[u8], len: usize) -> u8 {
     let mut result: u8 = 0x00;
@@ -12,7 +12,27 @@ pub fn xor_checksum(buf: &[u8], len: usize) -> u8 {
     return result;
 }
 
-pub fn link_tx_lock(link_tx: &Arc<Mutex<LinkTx>>) -> Option<MutexGuard<'_, LinkTx>> {
+pub fn to_hex(buf: &[u8]) -> String {
+    buf.iter()
+        .map(|v| format!("{:02X}", v))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn link_tx_lock(link_tx: &Arc<Mutex<Box<dyn MqttLink>>>) -> Option<MutexGuard<'_, Box<dyn MqttLink>>> {
     match link_tx.lock() {
         Ok(v) => Some(v),
         Err(e) => {
@@ -31,7 +51,7 @@ pub trait DurationUtils {
 
 impl DurationUtils for Duration {
     fn from_minutes(value: u64) -> Duration {
-        Duration::from_secs(value * 60)
+        Duration::from_secs(value.saturating_mul(60))
     }
 
     fn as_minutes(&self) -> u64 {